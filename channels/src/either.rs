@@ -0,0 +1,118 @@
+//! Branching on what the other side sent.
+//!
+//! Each message is preceded by a tag, saying which of the types it could be
+//! it is, so the receiver knows what to decode it as. The tag is the index of
+//! the type, as a `u32`, which is how a serde enum is sent too: sending an
+//! `Either` or any enum of single value variants is the same as sending
+//! its value with `send_tagged`.
+//!
+//! ```rust,ignore
+//! match channel.recv_either::<Admit, Reject>()? {
+//!     Either::Left(admit) => ...,
+//!     Either::Right(reject) => ...,
+//! }
+//!
+//! // For more than two types.
+//! match channel.recv_tag()? {
+//!     0 => ping(channel.recv::<Ping>()?),
+//!     1 => data(channel.recv::<Data>()?),
+//!     2 => quit(channel.recv::<Quit>()?),
+//!     tag => return Err(Either::<(), ()>::unexpected(tag)),
+//! }
+//! ```
+use std::fmt::Debug;
+use std::io::{Error, ErrorKind};
+use serde::{Serialize, Deserialize};
+use crate::Channel;
+
+/// One of two types.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<T, U> {
+    Left(T),
+    Right(U),
+}
+
+impl<T, U> Either<T, U> {
+    /// The error for a tag that isn't any of the expected types.
+    pub fn unexpected(tag: u32) -> Error {
+        Error::new(ErrorKind::InvalidData, format!("unexpected tag: {}", tag))
+    }
+}
+
+/// Tagged messages.
+impl Channel {
+    /// Send `message` as the type with this tag.
+    pub fn send_tagged<T: Serialize + Debug>(&mut self, tag: u32, message: &T) -> Result<(), Error> {
+        self.send(&(tag, message))
+    }
+
+    /// Receive the tag of the next message, which should then be received
+    /// as the type it says, even if that's `()`.
+    pub fn recv_tag(&mut self) -> Result<u32, Error> {
+        self.3 = false;
+        let tag = self.recv()?;
+        self.3 = true;
+        Ok(tag)
+    }
+
+    /// Receive a message which is either a `T`, tagged 0, or a `U`, tagged 1.
+    pub fn recv_either<T, U>(&mut self) -> Result<Either<T, U>, Error>
+        where T: for<'de> Deserialize<'de> + Debug,
+              U: for<'de> Deserialize<'de> + Debug,
+    {
+        match self.recv_tag()? {
+            0 => Ok(Either::Left(self.recv()?)),
+            1 => Ok(Either::Right(self.recv()?)),
+            tag => Err(Either::<T, U>::unexpected(tag)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recv_either() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        a.send_tagged(1, &"hi").unwrap();
+        a.send(&Either::<String, u64>::Left("hi".into())).unwrap();
+        a.send_tagged(0, &7u64).unwrap();
+        assert_eq!(Either::Right("hi".to_string()), b.recv_either::<u64, String>().unwrap());
+        assert_eq!(Either::Left("hi".to_string()), b.recv_either::<String, u64>().unwrap());
+        assert_eq!(Either::Left(7), b.recv_either::<u64, String>().unwrap());
+
+        a.send_tagged(2, &()).unwrap();
+        let error = b.recv_either::<u64, String>().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn recv_tag() {
+        #[derive(Serialize, Debug)]
+        enum Message {
+            Ping,
+            Data(Vec<u8>),
+            Quit(String),
+        }
+
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        a.send(&Message::Data(vec![1, 2])).unwrap();
+        a.send(&Message::Ping).unwrap();
+        a.send_tagged(0, &()).unwrap();
+        a.send(&Message::Quit("bye".into())).unwrap();
+        let mut seen = vec![];
+        loop {
+            match b.recv_tag().unwrap() {
+                0 => seen.push("ping".to_string()),
+                1 => seen.push(format!("{:?}", b.recv::<Vec<u8>>().unwrap())),
+                2 => {
+                    seen.push(b.recv::<String>().unwrap());
+                    break;
+                }
+                tag => panic!("{}", Either::<(), ()>::unexpected(tag)),
+            }
+        }
+        assert_eq!(vec!["[1, 2]", "ping", "ping", "bye"], seen);
+    }
+}
@@ -0,0 +1,305 @@
+//! Fault injection, for testing code against a misbehaving network.
+//!
+//! `Faulty` wraps another transport, and applies `Fault`s at given byte
+//! offsets into the stream written or read through it. Offsets count the
+//! bytes of the stream as the channel sees it, including the handshake.
+//!
+//! ```rust,ignore
+//! let (a, b) = memory::pair();
+//! // Flip the bits of bytes 8 and 9 we send, then hang up at byte 32.
+//! let faulty = Faulty::new(a).on_write(8, Fault::Corrupt(2)).on_write(32, Fault::Close);
+//! let channel = Channel::connect_to_transport("nixpulvis".into(), faulty)?;
+//! ```
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use log::info;
+use crate::transport::Transport;
+
+/// Something that can go wrong with a stream of bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// Wait before carrying on with the stream.
+    Delay(Duration),
+    /// Silently lose the next `n` bytes.
+    Drop(usize),
+    /// Deliver the next `n` bytes twice.
+    Duplicate(usize),
+    /// Flip every bit of the next `n` bytes.
+    Corrupt(usize),
+    /// Silently lose the rest of the stream, and close it.
+    Truncate,
+    /// Close the stream, failing any further use of it.
+    Close,
+}
+
+/// What a direction of the stream has come to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Open,
+    Truncated,
+    Closed,
+}
+
+/// The faults for one direction of the stream.
+#[derive(Default)]
+struct Faults {
+    pending: VecDeque<(usize, Fault)>,
+    active: Option<(Fault, usize)>,
+    offset: usize,
+}
+
+impl Faults {
+    fn add(&mut self, at: usize, fault: Fault) {
+        let i = self.pending.iter().take_while(|(o, _)| *o <= at).count();
+        self.pending.insert(i, (at, fault));
+    }
+
+    /// Pass `input` through the faults, onto the end of `out`.
+    fn apply(&mut self, input: &[u8], out: &mut Vec<u8>) -> State {
+        for b in input {
+            while self.pending.front().map(|(o, _)| *o) == Some(self.offset) {
+                let (_, fault) = self.pending.pop_front().unwrap();
+                info!("injecting {:?} at byte {}", fault, self.offset);
+                match fault {
+                    Fault::Delay(d) => thread::sleep(d),
+                    Fault::Truncate => return State::Truncated,
+                    Fault::Close => return State::Closed,
+                    Fault::Drop(n) | Fault::Duplicate(n) | Fault::Corrupt(n) if n > 0 => {
+                        self.active = Some((fault, n));
+                    }
+                    _ => {}
+                }
+            }
+            self.offset += 1;
+            let remaining = match &mut self.active {
+                None => {
+                    out.push(*b);
+                    continue;
+                }
+                Some((fault, n)) => {
+                    match fault {
+                        Fault::Duplicate(_) => out.extend(&[*b, *b]),
+                        Fault::Corrupt(_) => out.push(!b),
+                        _ => {}
+                    }
+                    *n -= 1;
+                    *n
+                }
+            };
+            if remaining == 0 {
+                self.active = None;
+            }
+        }
+        State::Open
+    }
+}
+
+/// A transport which injects faults into another.
+pub struct Faulty<T> {
+    inner: T,
+    writes: Faults,
+    reads: Faults,
+    state: State,
+    pending: VecDeque<u8>,
+}
+
+impl<T: Transport> Faulty<T> {
+    pub fn new(inner: T) -> Faulty<T> {
+        Faulty {
+            inner,
+            writes: Faults::default(),
+            reads: Faults::default(),
+            state: State::Open,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Inject `fault` when the `at`th byte is written.
+    pub fn on_write(mut self, at: usize, fault: Fault) -> Faulty<T> {
+        self.writes.add(at, fault);
+        self
+    }
+
+    /// Inject `fault` when the `at`th byte is read.
+    pub fn on_read(mut self, at: usize, fault: Fault) -> Faulty<T> {
+        self.reads.add(at, fault);
+        self
+    }
+
+    fn end(&mut self, state: State) {
+        if state != State::Open && self.state == State::Open {
+            info!("faulty transport {:?}", state);
+            self.state = state;
+            self.inner.shutdown().ok();
+        }
+    }
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::ConnectionReset, "connection closed by fault")
+}
+
+impl<T: Transport> Read for Faulty<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pending.is_empty() {
+            match self.state {
+                State::Open => {}
+                State::Truncated => return Ok(0),
+                State::Closed => return Err(closed()),
+            }
+            let mut input = vec![0; buf.len().max(1)];
+            let n = self.inner.read(&mut input)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut out = vec![];
+            let state = self.reads.apply(&input[..n], &mut out);
+            self.pending.extend(out);
+            self.end(state);
+        }
+        let n = buf.len().min(self.pending.len());
+        for (b, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Transport> Write for Faulty<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.state {
+            State::Open => {}
+            State::Truncated => return Ok(buf.len()),
+            State::Closed => return Err(closed()),
+        }
+        let mut out = vec![];
+        let state = self.writes.apply(buf, &mut out);
+        self.inner.write_all(&out)?;
+        self.end(state);
+        if state == State::Closed {
+            Err(closed())
+        } else {
+            Ok(buf.len())
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Faulty<T> {
+    fn is_ready(&self) -> Result<bool, Error> {
+        if !self.pending.is_empty() || self.state != State::Open {
+            Ok(true)
+        } else {
+            self.inner.is_ready()
+        }
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        if !self.pending.is_empty() || self.state != State::Open {
+            Ok(true)
+        } else {
+            self.inner.wait_ready(timeout)
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.inner.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.inner.peer_addr()
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::Channel;
+    use crate::memory;
+    use super::*;
+
+    /// Connect a channel with faults on its transport. The handshake takes
+    /// the first 21 bytes written ("nixpulvis") and 8 bytes read (`Ok(())`), each
+    /// message starting with a 4 byte frame header.
+    fn faulty<F>(faults: F) -> (Channel, Channel)
+        where F: FnOnce(Faulty<memory::Memory>) -> Faulty<memory::Memory>
+    {
+        let (a, b) = memory::pair();
+        let t = thread::spawn(move || Channel::accept_from_transport(b).unwrap());
+        let c = Channel::connect_to_transport("nixpulvis".into(), faults(Faulty::new(a))).unwrap();
+        (c, t.join().unwrap())
+    }
+
+    #[test]
+    fn corrupt() {
+        let (mut c, mut s) = faulty(|f| f.on_write(25, Fault::Corrupt(1)));
+        c.send(&1u8).unwrap();
+        assert_eq!(!1u8, s.recv::<u8>().unwrap());
+        c.send(&1u8).unwrap();
+        assert_eq!(1u8, s.recv::<u8>().unwrap());
+    }
+
+    #[test]
+    fn duplicate() {
+        let (mut c, mut s) = faulty(|f| f.on_write(25, Fault::Duplicate(1)));
+        c.send(&7u8).unwrap();
+        c.send(&8u8).unwrap();
+        assert_eq!(7u8, s.recv::<u8>().unwrap());
+        // The extra byte is read as the start of the next frame's header.
+        assert!(s.recv::<u8>().is_err());
+    }
+
+    #[test]
+    fn drop_bytes() {
+        let (mut c, mut s) = faulty(|f| f.on_write(25, Fault::Drop(4)));
+        c.send(&1u64).unwrap();
+        drop(c);
+        // Only half the number arrived before the hang up.
+        assert!(s.recv::<u64>().is_err());
+    }
+
+    #[test]
+    fn truncate() {
+        let (mut c, mut s) = faulty(|f| f.on_write(28, Fault::Truncate));
+        assert!(c.send(&"hello".to_string()).is_ok());
+        assert!(c.send(&1u8).is_ok());
+        assert!(s.recv::<String>().is_err());
+    }
+
+    #[test]
+    fn close() {
+        let (mut c, mut s) = faulty(|f| f.on_read(8, Fault::Close));
+        s.send(&1u8).unwrap();
+        let error = c.recv::<u8>().unwrap_err();
+        assert!(error.to_string().contains("connection closed by fault"));
+        assert!(c.send(&1u8).is_err());
+        assert!(s.recv::<u8>().is_err());
+    }
+
+    #[test]
+    fn delay() {
+        let (mut c, mut s) = faulty(|f| f.on_read(8, Fault::Delay(Duration::from_millis(50))));
+        let start = Instant::now();
+        s.send(&1u8).unwrap();
+        assert_eq!(1u8, c.recv::<u8>().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn rpc_truncated_reply() {
+        let (mut c, mut s) = faulty(|f| f.on_read(14, Fault::Truncate));
+        let t = thread::spawn(move || s.accept_call(&|n: &u64| n.to_string()));
+        assert!(c.call::<u64, String>(&1234).is_err());
+        assert!(t.join().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,309 @@
+//! Keepalive pings, and noticing when the other side has gone.
+//!
+//! A channel with a heartbeat pings the other side every `interval`, and
+//! counts anything it hears as a sign of life. Pings are skipped over by
+//! `recv`, but once `misses` intervals pass in silence the peer is taken to be
+//! dead: the connection is closed, and `recv` fails with `ErrorKind::TimedOut`
+//! instead of blocking forever.
+//!
+//! Both sides of a channel need a heartbeat, since it changes what's sent.
+//! Received data waits to be read in a bounded buffer, and once that's full
+//! nothing more is read from the connection until some of it is.
+//!
+//! ```rust,ignore
+//! let channel = Channel::accept_from_socket_addr(addr)?.heartbeat(Heartbeat::default())?;
+//! ```
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use log::info;
+use crate::Channel;
+use crate::transport::Transport;
+
+/// How often to ping, and how many pings can go missing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    pub misses: u32,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Heartbeat {
+        Heartbeat { interval: Duration::from_secs(1), misses: 3 }
+    }
+}
+
+/// How many received bytes are kept waiting to be read before the reader
+/// stops taking in more, until some are read.
+const BUFFER_LIMIT: usize = 1 << 20;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Frame {
+    Data(Vec<u8>),
+    Ping,
+}
+
+struct Shared {
+    writer: Mutex<Box<dyn Transport>>,
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    buffer: VecDeque<u8>,
+    last_seen: Instant,
+    eof: bool,
+    dead: bool,
+    stopped: bool,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn send(&self, frame: &Frame) -> Result<(), Error> {
+        let bytes = bincode::serialize(frame).map_err(Error::other)?;
+        self.writer.lock().unwrap().write_all(&bytes)
+    }
+
+    /// Read frames until the stream closes, or we stop.
+    fn read(&self, mut reader: Box<dyn Transport>) {
+        loop {
+            {
+                let mut state = self.state();
+                if state.buffer.len() >= BUFFER_LIMIT {
+                    state = self.changed
+                        .wait_while(state, |s| s.buffer.len() >= BUFFER_LIMIT && !s.stopped)
+                        .unwrap();
+                }
+                if state.stopped {
+                    return;
+                }
+            }
+            let frame = bincode::deserialize_from(&mut reader);
+            let mut state = self.state();
+            match frame {
+                Ok(Frame::Data(bytes)) => state.buffer.extend(bytes),
+                Ok(Frame::Ping) => {}
+                Err(_) => {
+                    state.eof = true;
+                    self.changed.notify_all();
+                    return;
+                }
+            }
+            state.last_seen = Instant::now();
+            self.changed.notify_all();
+        }
+    }
+
+    /// Ping every interval, until stopped or the peer's dead.
+    fn ping(&self, heartbeat: Heartbeat) {
+        let mut state = self.state();
+        while !state.stopped && !state.eof {
+            // While the buffer's full the peer's pings aren't being read.
+            let reading = state.buffer.len() < BUFFER_LIMIT;
+            if reading && state.last_seen.elapsed() > heartbeat.interval * heartbeat.misses {
+                info!("peer dead, no heartbeat in {:?}", state.last_seen.elapsed());
+                state.dead = true;
+                self.changed.notify_all();
+                drop(state);
+                self.writer.lock().unwrap().shutdown().ok();
+                return;
+            }
+            drop(state);
+            if self.send(&Frame::Ping).is_err() {
+                return;
+            }
+            state = self.state();
+            let next = Instant::now() + heartbeat.interval;
+            while !state.stopped && Instant::now() < next {
+                let timeout = next.saturating_duration_since(Instant::now());
+                state = self.changed.wait_timeout(state, timeout).unwrap().0;
+            }
+        }
+    }
+}
+
+/// Heartbeats.
+impl Channel {
+    /// Add a heartbeat to this channel, which fails if the transport can't be
+    /// cloned. See the module documentation.
+    pub fn heartbeat(mut self, heartbeat: Heartbeat) -> Result<Channel, Error> {
+        let transport = self.1.try_clone()?;
+        self.1 = Box::new(Beating::new(transport, heartbeat)?);
+        Ok(self)
+    }
+}
+
+/// A transport with a heartbeat.
+struct Beating {
+    shared: Arc<Shared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Beating {
+    fn new(transport: Box<dyn Transport>, heartbeat: Heartbeat) -> Result<Beating, Error> {
+        let reader = transport.try_clone()?;
+        let shared = Arc::new(Shared {
+            writer: Mutex::new(transport),
+            state: Mutex::new(State {
+                buffer: VecDeque::new(),
+                last_seen: Instant::now(),
+                eof: false,
+                dead: false,
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let (r, p) = (shared.clone(), shared.clone());
+        let threads = vec![
+            thread::spawn(move || r.read(reader)),
+            thread::spawn(move || p.ping(heartbeat)),
+        ];
+        Ok(Beating { shared, threads })
+    }
+}
+
+impl Read for Beating {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.shared.state();
+        while state.buffer.is_empty() {
+            if state.dead {
+                return Err(Error::new(ErrorKind::TimedOut, "peer dead, missed heartbeats"));
+            }
+            if state.eof {
+                return Ok(0);
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        if state.buffer.len() >= BUFFER_LIMIT {
+            // Pings weren't read while the buffer was full, so don't hold
+            // that against the peer.
+            state.last_seen = Instant::now();
+        }
+        let n = buf.len().min(state.buffer.len());
+        for (b, byte) in buf.iter_mut().zip(state.buffer.drain(..n)) {
+            *b = byte;
+        }
+        // There may be room for the reader again.
+        self.shared.changed.notify_all();
+        Ok(n)
+    }
+}
+
+impl Write for Beating {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.shared.send(&Frame::Data(buf.to_vec()))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for Beating {
+    fn is_ready(&self) -> Result<bool, Error> {
+        let state = self.shared.state();
+        Ok(!state.buffer.is_empty() || state.eof || state.dead)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        let state = self.shared.state();
+        let (state, _) = self.shared.changed
+            .wait_timeout_while(state, timeout, |s| s.buffer.is_empty() && !s.eof && !s.dead)
+            .unwrap();
+        Ok(!state.buffer.is_empty() || state.eof || state.dead)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.shared.writer.lock().unwrap().local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.shared.writer.lock().unwrap().peer_addr()
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        self.shared.writer.lock().unwrap().shutdown()
+    }
+}
+
+impl Drop for Beating {
+    fn drop(&mut self) {
+        self.shared.state().stopped = true;
+        self.shared.changed.notify_all();
+        self.shutdown().ok();
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory;
+    use super::*;
+
+    const FAST: Heartbeat = Heartbeat { interval: Duration::from_millis(10), misses: 3 };
+
+    #[test]
+    fn idle() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (mut a, mut b) = (a.heartbeat(FAST).unwrap(), b.heartbeat(FAST).unwrap());
+        // Many intervals of silence, apart from the pings.
+        thread::sleep(Duration::from_millis(100));
+        a.send(&1u64).unwrap();
+        assert_eq!(1u64, b.recv::<u64>().unwrap());
+    }
+
+    #[test]
+    fn dead_peer() {
+        // The other side is still connected, but never says anything.
+        let (a, _b) = Channel::memory_pair("nixpulvis".into());
+        let mut a = a.heartbeat(FAST).unwrap();
+        let start = Instant::now();
+        let error = a.recv::<u64>().unwrap_err();
+        assert!(error.to_string().contains("peer dead"));
+        assert!(start.elapsed() >= FAST.interval * FAST.misses);
+    }
+
+    #[test]
+    fn full_buffer() {
+        let (a, mut b) = memory::pair();
+        // Big frames are slow to decode, so give them time to arrive.
+        let heartbeat = Heartbeat { interval: Duration::from_millis(50), misses: 3 };
+        let mut a = Beating::new(Box::new(a), heartbeat).unwrap();
+        let frame = bincode::serialize(&Frame::Data(vec![1; 64 << 10])).unwrap();
+        let frames = BUFFER_LIMIT / (64 << 10) + 4;
+        let t = thread::spawn(move || {
+            for _ in 0..frames {
+                b.write_all(&frame).unwrap();
+            }
+            b
+        });
+
+        // The reader stops once the buffer's full, and the peer isn't taken
+        // to be dead for its pings going unread.
+        thread::sleep(heartbeat.interval * heartbeat.misses * 2);
+        assert!(a.shared.state().buffer.len() < BUFFER_LIMIT + (64 << 10));
+        let mut bytes = vec![0; frames * (64 << 10)];
+        a.read_exact(&mut bytes).unwrap();
+        assert!(bytes.iter().all(|b| *b == 1));
+        drop(t.join().unwrap());
+    }
+
+    #[test]
+    fn close() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (mut a, b) = (a.heartbeat(FAST).unwrap(), b.heartbeat(FAST).unwrap());
+        drop(b);
+        let error = a.recv::<u64>().unwrap_err();
+        assert!(!error.to_string().contains("peer dead"));
+    }
+}
@@ -0,0 +1,467 @@
+//! JSON-RPC 2.0, for calling and being called by things which aren't
+//! `channels`.
+//!
+//! A `JsonRpc` speaks JSON-RPC over a channel instead of bincode, with one
+//! message per line or each prefixed by its length, and the same typed `call`
+//! and `accept_call` as a `Channel`. Methods are named, as JSON-RPC requires.
+//!
+//! A method's error is sent as an error object whose `data` is the error
+//! itself, and comes back from `try_call` as a `RemoteError<E>`. Anything else
+//! the other side objects to comes back as an `ErrorObject`.
+//!
+//! ```rust,ignore
+//! let mut rpc = JsonRpc::new(channel, Framing::Lines);
+//! let sum: u64 = rpc.call("add", &(1, 2))?;
+//! ```
+use std::error;
+use std::fmt::{self, Debug, Display};
+use std::io::{Error, ErrorKind, Read, Write};
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+use log::info;
+use crate::{Channel, RemoteError};
+use crate::transcript::Direction;
+
+/// How messages are separated on the wire.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Each message is a line of JSON.
+    Lines,
+    /// Each message is preceded by its length in bytes, as a big endian `u32`.
+    Length,
+}
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The code of errors returned by a method itself.
+pub const METHOD_ERROR: i64 = -32000;
+
+/// The largest message received by default, in bytes.
+pub const MAX_MESSAGE: usize = 1 << 20;
+
+/// A JSON-RPC error object.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorObject {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl ErrorObject {
+    pub fn new(code: i64, message: impl Into<String>) -> ErrorObject {
+        ErrorObject { code, message: message.into(), data: None }
+    }
+
+    /// The error object inside an error returned by `try_call`, if the other
+    /// side sent one.
+    pub fn downcast(error: &Error) -> Option<&ErrorObject> {
+        error.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    fn to_value(&self) -> Value {
+        let mut value = json!({ "code": self.code, "message": self.message });
+        if let Some(data) = &self.data {
+            value["data"] = data.clone();
+        }
+        value
+    }
+
+    fn from_value(value: &Value) -> Option<ErrorObject> {
+        Some(ErrorObject {
+            code: value.get("code")?.as_i64()?,
+            message: value.get("message")?.as_str()?.into(),
+            data: value.get("data").cloned(),
+        })
+    }
+}
+
+impl Display for ErrorObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "json-rpc error {}: {}", self.code, self.message)
+    }
+}
+
+impl error::Error for ErrorObject {}
+
+impl From<ErrorObject> for Error {
+    fn from(error: ErrorObject) -> Error {
+        let kind = match error.code {
+            PARSE_ERROR | INVALID_REQUEST => ErrorKind::InvalidData,
+            METHOD_NOT_FOUND => ErrorKind::Unsupported,
+            INVALID_PARAMS => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        Error::new(kind, error)
+    }
+}
+
+/// JSON-RPC over a channel, see the module documentation.
+pub struct JsonRpc {
+    channel: Channel,
+    framing: Framing,
+    /// Bytes read past the end of the last message.
+    buffer: Vec<u8>,
+    max_message: usize,
+    next: u64,
+}
+
+impl JsonRpc {
+    pub fn new(channel: Channel, framing: Framing) -> JsonRpc {
+        JsonRpc { channel, framing, buffer: vec![], max_message: MAX_MESSAGE, next: 0 }
+    }
+
+    /// The largest message to receive, in bytes. Anything larger is answered
+    /// with an `INVALID_REQUEST` error, and the connection is closed.
+    pub fn max_message(mut self, bytes: usize) -> JsonRpc {
+        self.max_message = bytes;
+        self
+    }
+
+    /// The channel back, losing anything read but not yet received.
+    pub fn into_inner(self) -> Channel {
+        self.channel
+    }
+
+    /// Call a method which always succeeds.
+    pub fn call<D, C>(&mut self, method: &str, params: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+    {
+        self.try_call::<D, C, String>(method, params)
+    }
+
+    /// Call a method which can fail, its error comes back as a
+    /// `RemoteError<E>`.
+    pub fn try_call<D, C, E>(&mut self, method: &str, params: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        let id = self.next;
+        self.next += 1;
+        let mut request = json!({ "jsonrpc": "2.0", "method": method, "id": id });
+        if let Some(params) = to_params(params)? {
+            request["params"] = params;
+        }
+        self.send(&request)?;
+
+        let invalid = |message| Error::new(ErrorKind::InvalidData, message);
+        let response = match self.recv() {
+            Ok(response) => response.map_err(|e| invalid(e.to_string()))?,
+            Err(e) => {
+                if ErrorObject::downcast(&e).is_some() {
+                    self.channel.1.shutdown()?;
+                }
+                return Err(e);
+            }
+        };
+        if response.get("id") != Some(&json!(id)) {
+            return Err(invalid(format!("response to the wrong request: {}", response)));
+        }
+        if let Some(error) = response.get("error") {
+            let error = ErrorObject::from_value(error)
+                .ok_or_else(|| invalid(format!("bad error object: {}", error)))?;
+            if let (METHOD_ERROR, Some(data)) = (error.code, &error.data) {
+                if let Ok(e) = serde_json::from_value::<E>(data.clone()) {
+                    return Err(Error::other(RemoteError(e)));
+                }
+            }
+            return Err(error.into());
+        }
+        let result = response.get("result").cloned().unwrap_or(Value::Null);
+        serde_json::from_value(result).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Call a method without waiting for, or getting, a reply.
+    pub fn notify<D: Serialize>(&mut self, method: &str, params: &D) -> Result<(), Error> {
+        let mut request = json!({ "jsonrpc": "2.0", "method": method });
+        if let Some(params) = to_params(params)? {
+            request["params"] = params;
+        }
+        self.send(&request)
+    }
+
+    /// Answer a call to `method` with `func`. A call to any other method is
+    /// answered with an error, and fails here with `ErrorKind::Unsupported`.
+    pub fn accept_call<D, C>(&mut self, method: &str, func: &dyn Fn(&D) -> C) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de>,
+              C: Serialize,
+    {
+        self.accept_try_call(method, &|d| Ok::<C, String>(func(d)))
+    }
+
+    /// Like `accept_call`, but for a method which can fail, in which case its
+    /// error is sent back to the caller, and returned here too.
+    pub fn accept_try_call<D, C, E>(&mut self, method: &str, func: &dyn Fn(&D) -> Result<C, E>) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de>,
+              C: Serialize,
+              E: Serialize + Debug + Display,
+    {
+        let request = match self.recv() {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                let error = ErrorObject::new(PARSE_ERROR, e.to_string());
+                self.respond(Some(Value::Null), Err(error.clone()))?;
+                return Err(error.into());
+            }
+            Err(e) => {
+                if let Some(error) = ErrorObject::downcast(&e).cloned() {
+                    self.respond(Some(Value::Null), Err(error))?;
+                    self.channel.1.shutdown()?;
+                }
+                return Err(e);
+            }
+        };
+        let id = request.get("id").cloned();
+        let name = match (request.get("jsonrpc"), request.get("method")) {
+            (Some(version), Some(Value::String(name))) if version == "2.0" => name,
+            _ => {
+                let error = ErrorObject::new(INVALID_REQUEST, format!("not a request: {}", request));
+                self.respond(Some(id.unwrap_or(Value::Null)), Err(error.clone()))?;
+                return Err(error.into());
+            }
+        };
+        if name != method {
+            let error = ErrorObject::new(METHOD_NOT_FOUND, format!("no method named {:?}", name));
+            self.respond(id, Err(error.clone()))?;
+            return Err(error.into());
+        }
+        let domain = match from_params(request.get("params").cloned()) {
+            Ok(domain) => domain,
+            Err(e) => {
+                let error = ErrorObject::new(INVALID_PARAMS, e.to_string());
+                self.respond(id, Err(error.clone()))?;
+                return Err(error.into());
+            }
+        };
+        match func(&domain) {
+            Ok(codomain) => {
+                let result = serde_json::to_value(&codomain)
+                    .map_err(|e| ErrorObject::new(INTERNAL_ERROR, e.to_string()));
+                self.respond(id, result)?;
+                Ok(domain)
+            }
+            Err(e) => {
+                let mut error = ErrorObject::new(METHOD_ERROR, e.to_string());
+                error.data = serde_json::to_value(&e).ok();
+                self.respond(id, Err(error))?;
+                Err(Error::other(format!("call failed: {}", e)))
+            }
+        }
+    }
+
+    /// Reply to a request, unless it was a notification, without an id.
+    fn respond(&mut self, id: Option<Value>, outcome: Result<Value, ErrorObject>) -> Result<(), Error> {
+        let id = match id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let response = match outcome {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => json!({ "jsonrpc": "2.0", "error": error.to_value(), "id": id }),
+        };
+        self.send(&response)
+    }
+
+    fn send(&mut self, message: &Value) -> Result<(), Error> {
+        let json = serde_json::to_vec(message).map_err(Error::other)?;
+        let bytes = match self.framing {
+            Framing::Lines => [&json[..], b"\n"].concat(),
+            Framing::Length => [&(json.len() as u32).to_be_bytes()[..], &json].concat(),
+        };
+        self.channel.1.write_all(&bytes)?;
+        if let Some(recorder) = &self.channel.2 {
+            recorder.lock().unwrap().record(Direction::Sent, &bytes)?;
+        }
+        info!("send({}) {:?}", message, self.channel.0);
+        Ok(())
+    }
+
+    /// Receive a message, which may not be valid JSON. A message which is too
+    /// large fails with an `INVALID_REQUEST` error object, after which the
+    /// channel can't be used.
+    fn recv(&mut self) -> Result<Result<Value, serde_json::Error>, Error> {
+        let too_large = || Error::from(ErrorObject::new(INVALID_REQUEST, "message too large"));
+        let (frame, json) = match self.framing {
+            Framing::Lines => {
+                let end = loop {
+                    match self.buffer.iter().position(|b| *b == b'\n') {
+                        Some(end) => break end + 1,
+                        None if self.buffer.len() > self.max_message => return Err(too_large()),
+                        None => self.fill()?,
+                    }
+                };
+                if end - 1 > self.max_message {
+                    return Err(too_large());
+                }
+                (end, 0..end - 1)
+            }
+            Framing::Length => {
+                self.fill_to(4)?;
+                let length = u32::from_be_bytes([self.buffer[0], self.buffer[1], self.buffer[2], self.buffer[3]]);
+                if length as usize > self.max_message {
+                    return Err(too_large());
+                }
+                let end = 4 + length as usize;
+                self.fill_to(end)?;
+                (end, 4..end)
+            }
+        };
+        if let Some(recorder) = &self.channel.2 {
+            recorder.lock().unwrap().record(Direction::Received, &self.buffer[..frame])?;
+        }
+        let message = serde_json::from_slice(&self.buffer[json]);
+        self.buffer.drain(..frame);
+        if let Ok(message) = &message {
+            info!("recv({}) {:?}", message, self.channel.0);
+        }
+        Ok(message)
+    }
+
+    fn fill_to(&mut self, length: usize) -> Result<(), Error> {
+        while self.buffer.len() < length {
+            self.fill()?;
+        }
+        Ok(())
+    }
+
+    fn fill(&mut self) -> Result<(), Error> {
+        let mut chunk = [0; 4096];
+        let n = self.channel.1.read(&mut chunk)?;
+        if n == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+        }
+        self.buffer.extend_from_slice(&chunk[..n]);
+        Ok(())
+    }
+}
+
+/// JSON-RPC params are an array or object, so anything else is sent as the
+/// only element of an array. Nothing, `()`, is sent as no params.
+fn to_params<D: Serialize>(params: &D) -> Result<Option<Value>, Error> {
+    match serde_json::to_value(params).map_err(Error::other)? {
+        Value::Null => Ok(None),
+        params @ Value::Array(_) | params @ Value::Object(_) => Ok(Some(params)),
+        param => Ok(Some(Value::Array(vec![param]))),
+    }
+}
+
+/// The inverse of `to_params`.
+fn from_params<D>(params: Option<Value>) -> Result<D, serde_json::Error>
+    where D: for<'de> Deserialize<'de>,
+{
+    let params = params.unwrap_or(Value::Null);
+    match serde_json::from_value(params.clone()) {
+        Ok(domain) => Ok(domain),
+        Err(e) => match params {
+            Value::Array(mut params) if params.len() == 1 => {
+                serde_json::from_value(params.remove(0)).map_err(|_| e)
+            }
+            _ => Err(e),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use crate::memory;
+    use super::*;
+
+    fn pair(framing: Framing) -> (JsonRpc, JsonRpc) {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        (JsonRpc::new(a, framing), JsonRpc::new(b, framing))
+    }
+
+    #[test]
+    fn calls() {
+        for framing in [Framing::Lines, Framing::Length] {
+            let (mut a, mut b) = pair(framing);
+            let t = thread::spawn(move || {
+                assert_eq!((1, 2), b.accept_call("add", &|(x, y): &(u64, u64)| x + y).unwrap());
+                assert!(b.accept_call("negate", &|b: &bool| !b).unwrap());
+                b.accept_call("hello", &|(): &()| "hi".to_string()).unwrap();
+                b.accept_call("notified", &|n: &u64| *n).unwrap();
+            });
+            assert_eq!(3u64, a.call::<_, u64>("add", &(1u64, 2u64)).unwrap());
+            assert!(!a.call::<_, bool>("negate", &true).unwrap());
+            assert_eq!("hi", a.call::<_, String>("hello", &()).unwrap());
+            a.notify("notified", &1u64).unwrap();
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let (mut a, mut b) = pair(Framing::Lines);
+        let t = thread::spawn(move || {
+            let divide = |(n, d): &(u64, u64)| n.checked_div(*d).ok_or("divide by zero");
+            assert!(b.accept_try_call("div", &divide).is_err());
+            assert_eq!(ErrorKind::Unsupported, b.accept_try_call("div", &divide).unwrap_err().kind());
+            assert_eq!(ErrorKind::InvalidInput, b.accept_try_call("div", &divide).unwrap_err().kind());
+        });
+        let error = a.try_call::<_, u64, String>("div", &(1u64, 0u64)).unwrap_err();
+        assert_eq!(Some(&"divide by zero".to_string()), RemoteError::<String>::downcast(&error));
+
+        let error = a.call::<_, u64>("mul", &(1u64, 0u64)).unwrap_err();
+        assert_eq!(METHOD_NOT_FOUND, ErrorObject::downcast(&error).unwrap().code);
+        let error = a.call::<_, u64>("div", &"one").unwrap_err();
+        assert_eq!(INVALID_PARAMS, ErrorObject::downcast(&error).unwrap().code);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn wire() {
+        // Something which only speaks JSON.
+        let (a, mut other) = memory::pair();
+        let mut rpc = JsonRpc::new(Channel(None, Box::new(a), None, false), Framing::Lines);
+        let t = thread::spawn(move || {
+            rpc.accept_call("add", &|(x, y): &(u64, u64)| x + y).unwrap();
+            assert!(rpc.accept_call("add", &|(x, y): &(u64, u64)| x + y).is_err());
+        });
+        other.write_all(b"{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":\"a\"}\n").unwrap();
+        other.write_all(b"{\"jsonrpc\": \"2.0\", \"method\": \"add\", \"params\": [1,\n").unwrap();
+        t.join().unwrap();
+
+        let mut replies = String::new();
+        let mut chunk = [0; 1024];
+        while replies.matches('\n').count() < 2 {
+            let n = other.read(&mut chunk).unwrap();
+            replies.push_str(std::str::from_utf8(&chunk[..n]).unwrap());
+        }
+        let replies = replies.lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(json!({ "jsonrpc": "2.0", "result": 3, "id": "a" }), replies[0]);
+        assert_eq!(json!(PARSE_ERROR), replies[1]["error"]["code"]);
+        assert_eq!(Value::Null, replies[1]["id"]);
+    }
+
+    #[test]
+    fn too_large() {
+        for framing in [Framing::Lines, Framing::Length] {
+            let (a, mut other) = memory::pair();
+            let mut rpc = JsonRpc::new(Channel(None, Box::new(a), None, false), framing).max_message(64);
+            let t = thread::spawn(move || {
+                let error = rpc.accept_call("add", &|(x, y): &(u64, u64)| x + y).unwrap_err();
+                assert_eq!(INVALID_REQUEST, ErrorObject::downcast(&error).unwrap().code);
+            });
+            let request = format!("{{\"jsonrpc\":\"2.0\",\"method\":\"add\",\"params\":[1,2],\"id\":\"{}\"}}", "a".repeat(64));
+            match framing {
+                Framing::Lines => other.write_all(&[request.as_bytes(), b"\n"].concat()).unwrap(),
+                Framing::Length => other.write_all(&(request.len() as u32).to_be_bytes()).unwrap(),
+            }
+            t.join().unwrap();
+
+            // The reply's an error, and then the connection's closed.
+            let mut reply = vec![];
+            other.read_to_end(&mut reply).unwrap();
+            let start = if framing == Framing::Length { 4 } else { 0 };
+            let reply: Value = serde_json::from_slice(&reply[start..]).unwrap();
+            assert_eq!(json!(INVALID_REQUEST), reply["error"]["code"]);
+        }
+    }
+}
@@ -1,13 +1,18 @@
 //! Bidirectional channel (accept, recv) and (connect, send).
 use std::fmt::{self, Debug};
-use std::io::{Error, ErrorKind};
+use std::io::{Error, ErrorKind, Write};
+use std::mem;
 use std::net::{ToSocketAddrs, TcpStream, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use serde::{Serialize, Deserialize};
 use log::{info, error};
 
 /// Sending and receiving *whole* wire messages.
 ///
-// TODO: Do we always want TCP?
+/// The last field is set once `recv_tag` has read the start of a message, so
+/// the `recv` for the rest of it doesn't expect a new frame.
+///
 // TODO: Option<Id> should be `enum Info`
 // ```rust
 // enum Info {
@@ -16,7 +21,7 @@ use log::{info, error};
 //     ...?
 // }
 // ```
-pub struct Channel(Option<String>, TcpStream);
+pub struct Channel(Option<String>, Box<dyn Transport>, Option<Arc<Mutex<Recorder>>>, bool);
 
 /// Channel information.
 impl Channel {
@@ -29,10 +34,23 @@ impl Channel {
 }
 
 mod rpc;
+pub use self::rpc::{RemoteError, Stream, Incoming, Deadline, Key, Results};
+pub mod transport;
+pub mod memory;
+pub mod fault;
+pub mod transcript;
+pub mod sim;
+pub mod mux;
+pub mod split;
+pub mod reliable;
+pub mod heartbeat;
+pub mod service;
+pub mod jsonrpc;
+pub mod either;
+pub use self::either::Either;
 
-// /// either type and Deserialize impl.
-// pub mod either;
-// use self::either::Either;
+use self::transport::Transport;
+use self::transcript::{Direction, Recorder, Tee};
 
 /// Channel establishment.
 ///
@@ -65,17 +83,22 @@ impl Channel {
         // }
     }
 
-    /// Accept from a tcp stream, we must get some "info" and return "ok".
-    /// TODO: <question> Boolean to tell channel it was not good?
+    /// Accept from a tcp stream, we must get some "info", and tell the client
+    /// whether it's accepted.
     pub fn accept_from_tcp_stream(stream: TcpStream) -> Result<Channel, Error> {
-        let mut channel = Channel(None, stream);
-        let id = channel.accept_call(&|id: &String| {
+        Self::accept_from_transport(stream)
+    }
+
+    /// Accept from any transport, the same way as a tcp stream.
+    pub fn accept_from_transport<T: Transport + 'static>(transport: T) -> Result<Channel, Error> {
+        let mut channel = Channel(None, Box::new(transport), None, false);
+        let id = channel.accept_try_call(&|id: &String| {
             // NOTE: This is obviously not the final dynamic check. But it shows
             // how we can do some logic before we truly establish the `Channel`.
             if id == "nixpulvis" {
-                "ok"
+                Ok(())
             } else {
-                "err"
+                Err(format!("unknown identity: {}", id))
             }
         })?;
         channel.0 = id.into();
@@ -94,29 +117,44 @@ impl Channel {
         // stream.set_nonblocking(true)?;
     }
 
-    /// Connect to a tcp stream, we'll send the "info" for this channel, we
-    /// must get back the response "ok".
+    /// Connect to a tcp stream, we'll send the "info" for this channel, and
+    /// must be accepted.
     pub fn connect_to_tcp_stream(info: String, stream: TcpStream) -> Result<Channel, Error> {
-        let mut channel = Channel(Some(info.clone()), stream);
-        let ack = channel.call::<String, String>(&info)?;
-        if ack == "ok" {
-            info!("authenticated: {:?}", info);
-            Ok(channel)
-        } else {
-            let error = format!("invalid channel ack: {}", ack);
-            Err(Error::new(ErrorKind::Other, error))
-        }
+        Self::connect_to_transport(info, stream)
+    }
+
+    /// Connect over any transport, the same way as a tcp stream.
+    pub fn connect_to_transport<T: Transport + 'static>(info: String, transport: T) -> Result<Channel, Error> {
+        let mut channel = Channel(Some(info.clone()), Box::new(transport), None, false);
+        channel.try_call::<String, (), String>(&info)?;
+        info!("authenticated: {:?}", info);
+        Ok(channel)
     }
 }
 
+/// What precedes each message on the wire. Only `Data` is followed by a
+/// message, the others are handled by the channel itself, so they can never
+/// be mistaken for one.
+#[derive(Serialize, Deserialize, Debug)]
+enum Frame {
+    /// A message follows.
+    Data,
+    /// The sender is closing the channel, see `Channel::close`.
+    Close,
+    /// The caller gave up on the call with this id, see
+    /// `Channel::call_with_deadline`.
+    Cancel(u64),
+    /// The result of the call with this id, or `None` if it was cancelled.
+    Answer(u64, Option<Vec<u8>>),
+}
+
 /// Message passing send, and receive functions.
 impl Channel {
     pub fn send<T: Serialize + Debug>(&mut self, message: &T) -> Result<(), Error> {
         // self.1.set_write_timeout(Some(Duration::from_secs(2)))?;
-        bincode::serialize_into(&mut self.1, message).map_err(|e| {
-            error!("error sending: {}", e);
-            Error::new(ErrorKind::Other, e)
-        })?;
+        let mut bytes = encode(&Frame::Data)?;
+        bytes.extend(encode(message)?);
+        self.write_frame(&bytes)?;
         info!("send({:?}) {:?}", message, self.0);
         Ok(())
     }
@@ -125,22 +163,139 @@ impl Channel {
     where for<'de> T: Deserialize<'de> + Debug
     {
         // self.1.set_read_timeout(Some(Duration::from_secs(2)))?;
-        let message = bincode::deserialize_from(&mut self.1).map_err(|e| {
-            error!("error receiving: {}", e);
-            Error::new(ErrorKind::Other, e)
-        })?;
+        let mut bytes = vec![];
+        if !mem::take(&mut self.3) {
+            loop {
+                match self.read::<Frame>(&mut bytes)? {
+                    Frame::Data => break,
+                    Frame::Close => return Err(peer_closed()),
+                    stale => {
+                        self.record_frame(Direction::Received, &bytes)?;
+                        bytes.clear();
+                        info!("skipping {:?} {:?}", stale, self);
+                    }
+                }
+            }
+        }
+        let message = self.read(&mut bytes)?;
+        self.record_frame(Direction::Received, &bytes)?;
         info!("recv({:?}) {:?}", message, self);
         Ok(message)
     }
 
-    // /// Receive an `Either<T, U>` type from the wire. This provides support for channel consumers
-    // /// to logically branch based on channel messages.
-    // pub fn recv_either<T, U>(&mut self) -> Result<Either<T, U>, Error>
-    //     where T: for<'de> Deserialize<'de> + Debug,
-    //           U: for<'de> Deserialize<'de> + Debug,
-    // {
-    //     Either::<T, U>::deserialize_from(&mut self.1)
-    // }
+    /// Send a frame with no message.
+    fn send_control(&mut self, frame: &Frame) -> Result<(), Error> {
+        let bytes = encode(frame)?;
+        self.write_frame(&bytes)
+    }
+
+    /// Receive the next frame, which a message shouldn't follow.
+    fn recv_control(&mut self) -> Result<Frame, Error> {
+        let mut bytes = vec![];
+        let frame = self.read(&mut bytes)?;
+        self.record_frame(Direction::Received, &bytes)?;
+        Ok(frame)
+    }
+
+    /// Write a whole frame, and record it.
+    fn write_frame(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.1.write_all(bytes)?;
+        self.record_frame(Direction::Sent, bytes)
+    }
+
+    /// Read one value, adding its bytes to `bytes` when recording.
+    fn read<T>(&mut self, bytes: &mut Vec<u8>) -> Result<T, Error>
+    where for<'de> T: Deserialize<'de>
+    {
+        let value = if self.2.is_some() {
+            let mut tee = Tee(&mut *self.1, mem::take(bytes));
+            let value = bincode::deserialize_from(&mut tee);
+            *bytes = tee.1;
+            value
+        } else {
+            bincode::deserialize_from(&mut self.1)
+        };
+        value.map_err(|e| {
+            error!("error receiving: {}", e);
+            // Keep the transport's own errors as they were.
+            match *e {
+                bincode::ErrorKind::Io(e) => e,
+                e => Error::other(e),
+            }
+        })
+    }
+
+    fn record_frame(&self, direction: Direction, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(recorder) = &self.2 {
+            recorder.lock().unwrap().record(direction, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+fn peer_closed() -> Error {
+    Error::new(ErrorKind::ConnectionAborted, "peer closed the channel")
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, Error> {
+    bincode::serialize(value).map_err(|e| {
+        error!("error sending: {}", e);
+        Error::other(e)
+    })
+}
+
+/// Message readiness.
+impl Channel {
+    /// Returns true when a call to `recv` would not block, either because
+    /// there's data waiting, or because the stream has closed or failed (in
+    /// which case `recv` reports the error).
+    pub fn is_ready(&self) -> Result<bool, Error> {
+        self.1.is_ready()
+    }
+
+    /// Wait up to `timeout` for `is_ready`, returning whether it is.
+    pub fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        self.1.wait_ready(timeout)
+    }
+
+    /// Block until one of `channels` is ready, returning the index of the
+    /// first which is. Channels over TCP are waited on all at once, others
+    /// are each waited on for a moment in turn.
+    pub fn select(channels: &[&Channel]) -> Result<usize, Error> {
+        if channels.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "select on no channels"));
+        }
+        transport::wait_any(&channels.iter().map(|c| &*c.1).collect::<Vec<_>>())
+    }
+}
+
+/// Channel closing.
+impl Channel {
+    /// End the channel cleanly, telling the other side we're done and waiting
+    /// for it to say the same. An error means the other side didn't close
+    /// cleanly: it hung up, crashed, or sent a message instead.
+    ///
+    /// Closing is its own kind of frame, so a message still waiting to be
+    /// received is never taken for it, whatever it holds.
+    pub fn close(mut self) -> Result<(), Error> {
+        info!("closing {:?}", self);
+        let unclean = |e| Error::new(ErrorKind::ConnectionAborted, format!("peer didn't close cleanly: {}", e));
+        let closed = self.send_control(&Frame::Close).and_then(|_| loop {
+            match self.recv_control()? {
+                Frame::Close => return Ok(()),
+                Frame::Data => return Err(Error::other("got a message")),
+                stale => info!("skipping {:?} {:?}", stale, self),
+            }
+        });
+        match closed {
+            Ok(()) => {
+                info!("closed {:?}", self);
+                self.1.shutdown().ok();
+                Ok(())
+            }
+            Err(e) => Err(unclean(e.to_string())),
+        }
+    }
 }
 
 impl Debug for Channel {
@@ -167,8 +322,8 @@ mod tests {
     use super::*;
 
     // TODO: Remove hard-coded "nixpulvis"
-    // Accept  = ?["nixpulvis"];⊕ [!["ok"],ε]
-    // Connect = !["nixpulvis"];& [?["ok"],ε]
+    // Accept  = ?["nixpulvis"];⊕ [![Ok(())],![Err(..)]]
+    // Connect = !["nixpulvis"];& [?[Ok(())],?[Err(..)]]
     #[test]
     fn accept_and_connect() {
         thread::spawn(move || {
@@ -215,10 +370,74 @@ mod tests {
         thread::sleep(Duration::from_millis(10));
         thread::spawn(move || {
             let mut channel = Channel::connect_to_socket_addr("nixpulvis".into(), "127.0.0.1:1337").unwrap();
-            assert_eq!(true, channel.call(&1u64).unwrap());
+            assert!(channel.call::<_, bool>(&1u64).unwrap());
         }).join().unwrap();
     }
 
+    #[test]
+    fn is_ready() {
+        let t = thread::spawn(move || {
+            let mut channel = Channel::accept_from_socket_addr("127.0.0.1:1339").unwrap();
+            assert!(!channel.is_ready().unwrap());
+            channel.send(&1u64).unwrap();
+            while !channel.is_ready().unwrap() {}
+            assert_eq!(2u64, channel.recv::<u64>().unwrap());
+        });
+        thread::sleep(Duration::from_millis(10));
+        let mut channel = Channel::connect_to_socket_addr("nixpulvis".into(), "127.0.0.1:1339").unwrap();
+        while !channel.is_ready().unwrap() {}
+        assert_eq!(1u64, channel.recv::<u64>().unwrap());
+        channel.send(&2u64).unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn select() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let (c, d) = Channel::memory_pair("nixpulvis".into());
+        b.send(&1u64).unwrap();
+        assert_eq!(0, Channel::select(&[&a, &c]).unwrap());
+        assert_eq!(1u64, a.recv::<u64>().unwrap());
+        drop(d);
+        assert_eq!(1, Channel::select(&[&a, &c]).unwrap());
+        assert_eq!(ErrorKind::InvalidInput, Channel::select(&[]).unwrap_err().kind());
+    }
+
+    #[test]
+    fn close() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || b.close());
+        assert!(a.close().is_ok());
+        assert!(t.join().unwrap().is_ok());
+
+        // Hanging up isn't closing.
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        drop(b);
+        assert_eq!(ErrorKind::ConnectionAborted, a.close().unwrap_err().kind());
+
+        // Neither is carrying on with the protocol.
+        let (a, mut b) = Channel::memory_pair("nixpulvis".into());
+        b.send(&1u64).unwrap();
+        let error = a.close().unwrap_err();
+        assert!(error.to_string().starts_with("peer didn't close cleanly"));
+    }
+
+    #[test]
+    fn close_with_message_waiting() {
+        // A waiting message is never taken for closing, whatever it holds.
+        let (a, mut b) = Channel::memory_pair("nixpulvis".into());
+        b.send(&u64::from_be_bytes(*b"closing!")).unwrap();
+        let error = a.close().unwrap_err();
+        assert_eq!("peer didn't close cleanly: got a message", error.to_string());
+
+        // And a message can't be received once the other side's closed.
+        let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || b.close());
+        assert_eq!(ErrorKind::ConnectionAborted, a.recv::<u64>().unwrap_err().kind());
+        drop(a);
+        assert!(t.join().unwrap().is_err());
+    }
+
     #[test]
     #[ignore]
     fn infinite_length_number() {
@@ -0,0 +1,176 @@
+//! In-memory transport, for running both ends of a channel in one process.
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use crate::Channel;
+use crate::transport::Transport;
+
+/// One end of an in-memory byte stream, see `pair`.
+pub struct Memory {
+    read: Arc<Pipe>,
+    write: Arc<Pipe>,
+    handles: Arc<AtomicUsize>,
+}
+
+/// A one way, unbounded buffer of bytes.
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<(VecDeque<u8>, bool)>,
+    changed: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Create two connected transports, bytes written to one are read from the
+/// other. Dropping either end (and all its clones) closes the stream in both
+/// directions.
+pub fn pair() -> (Memory, Memory) {
+    let (a, b) = (Arc::new(Pipe::default()), Arc::new(Pipe::default()));
+    let handles = || Arc::new(AtomicUsize::new(1));
+    (Memory { read: a.clone(), write: b.clone(), handles: handles() },
+     Memory { read: b, write: a, handles: handles() })
+}
+
+/// In-memory channels.
+impl Channel {
+    /// Create two connected channels, already authenticated as `info`.
+    pub fn memory_pair(info: String) -> (Channel, Channel) {
+        let (a, b) = pair();
+        (Channel(Some(info.clone()), Box::new(a), None, false), Channel(Some(info), Box::new(b), None, false))
+    }
+}
+
+impl Read for Memory {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.read.state.lock().unwrap();
+        while state.0.is_empty() && !state.1 {
+            state = self.read.changed.wait(state).unwrap();
+        }
+        let n = buf.len().min(state.0.len());
+        for (b, byte) in buf.iter_mut().zip(state.0.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Memory {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.write.state.lock().unwrap();
+        if state.1 {
+            return Err(Error::new(ErrorKind::BrokenPipe, "memory transport closed"));
+        }
+        state.0.extend(buf);
+        self.write.changed.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for Memory {
+    fn is_ready(&self) -> Result<bool, Error> {
+        let state = self.read.state.lock().unwrap();
+        Ok(!state.0.is_empty() || state.1)
+    }
+
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        let state = self.read.state.lock().unwrap();
+        let (state, _) = self.read.changed
+            .wait_timeout_while(state, timeout, |state| state.0.is_empty() && !state.1)
+            .unwrap();
+        Ok(!state.0.is_empty() || state.1)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "memory transport"))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "memory transport"))
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        self.read.close();
+        self.write.close();
+        Ok(())
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        self.handles.fetch_add(1, Ordering::SeqCst);
+        Ok(Box::new(Memory {
+            read: self.read.clone(),
+            write: self.write.clone(),
+            handles: self.handles.clone(),
+        }))
+    }
+}
+
+impl Drop for Memory {
+    fn drop(&mut self) {
+        if self.handles.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.read.close();
+            self.write.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn send_recv() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            let n: u64 = b.recv().unwrap();
+            b.send(&(n + 1)).unwrap();
+        });
+        a.send(&1u64).unwrap();
+        assert_eq!(2u64, a.recv::<u64>().unwrap());
+        t.join().unwrap();
+        assert_eq!("nixpulvis", a.info());
+    }
+
+    #[test]
+    fn authenticate() {
+        let (a, b) = pair();
+        let t = thread::spawn(move || Channel::accept_from_transport(a).unwrap());
+        let c = Channel::connect_to_transport("nixpulvis".into(), b).unwrap();
+        assert_eq!("nixpulvis", t.join().unwrap().info());
+        assert_eq!("nixpulvis", c.info());
+    }
+
+    #[test]
+    fn rejected() {
+        use crate::RemoteError;
+
+        let (a, b) = pair();
+        let t = thread::spawn(move || Channel::accept_from_transport(a));
+        let error = Channel::connect_to_transport("mallory".into(), b).unwrap_err();
+        let reason = RemoteError::<String>::downcast(&error);
+        assert_eq!(Some(&"unknown identity: mallory".to_string()), reason);
+        assert!(t.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn close() {
+        let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+        assert!(!a.is_ready().unwrap());
+        drop(b);
+        assert!(a.is_ready().unwrap());
+        assert!(a.recv::<u64>().is_err());
+        assert!(a.send(&1u64).is_err());
+    }
+}
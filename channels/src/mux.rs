@@ -0,0 +1,500 @@
+//! Many logical channels over one stream.
+//!
+//! A `Mux` takes an established channel, and carries any number of streams
+//! over it, each of which is a `Channel` of its own with the same info. Either
+//! side can `open` a stream, which the other side gets from `accept`.
+//!
+//! Each stream has its own flow control: a side may only have `WINDOW` bytes
+//! of a stream unread by the other side at once, so a slow reader of one
+//! stream doesn't hold up the rest.
+//!
+//! ```rust,ignore
+//! let mut mux = Mux::new(Channel::connect_to_socket_addr(info, addr)?)?;
+//! let mut a = mux.open()?;
+//! let mut b = mux.open()?;
+//! a.send(&1u8)?;
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use serde::{Serialize, Deserialize};
+use log::{info, error};
+use crate::Channel;
+use crate::transport::Transport;
+
+/// How many bytes of a stream may be in flight, unread, at once.
+pub const WINDOW: usize = 64 * 1024;
+
+/// The most bytes of a stream sent in one frame.
+const MAX_FRAME: usize = 16 * 1024;
+
+/// A stream id, made of whether the sender of the frame opened the stream,
+/// and a number unique among the streams its opener opened.
+type Id = (bool, u32);
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Frame {
+    Open(Id),
+    Data(Id, Vec<u8>),
+    /// The receiver has read this many more bytes.
+    Window(Id, u32),
+    Close(Id),
+    /// The sender gave up on the stream, the other side broke its protocol.
+    Reset(Id),
+}
+
+/// Carries many channels over one, see the module documentation.
+pub struct Mux {
+    shared: Arc<Shared>,
+    reader: Option<JoinHandle<()>>,
+}
+
+struct Shared {
+    info: Option<String>,
+    writer: Mutex<Channel>,
+    streams: Mutex<Streams>,
+    changed: Condvar,
+}
+
+/// Streams keyed by `(we opened it, number)`.
+#[derive(Default)]
+struct Streams {
+    map: HashMap<Id, Stream>,
+    incoming: VecDeque<Id>,
+    next: u32,
+    closed: bool,
+}
+
+struct Stream {
+    buffer: VecDeque<u8>,
+    /// Bytes we may still send.
+    credit: usize,
+    /// Bytes read, but not yet reported back to the sender.
+    consumed: usize,
+    /// They closed the stream.
+    eof: bool,
+    /// We closed the stream.
+    closed: bool,
+    /// The stream was reset, and everything on it fails like this.
+    reset: Option<(ErrorKind, String)>,
+}
+
+impl Stream {
+    fn new() -> Stream {
+        Stream {
+            buffer: VecDeque::new(),
+            credit: WINDOW,
+            consumed: 0,
+            eof: false,
+            closed: false,
+            reset: None,
+        }
+    }
+
+    fn reset(&mut self, kind: ErrorKind, message: &str) {
+        self.buffer.clear();
+        self.reset = Some((kind, message.into()));
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        match &self.reset {
+            Some((kind, message)) => Err(Error::new(*kind, message.clone())),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Shared {
+    fn streams(&self) -> MutexGuard<'_, Streams> {
+        self.streams.lock().unwrap()
+    }
+
+    fn wait<'a>(&self, streams: MutexGuard<'a, Streams>) -> MutexGuard<'a, Streams> {
+        self.changed.wait(streams).unwrap()
+    }
+
+    fn send(&self, frame: &Frame) -> Result<(), Error> {
+        self.writer.lock().unwrap().send(frame)
+    }
+
+    /// Read and dispatch frames until the underlying channel closes.
+    fn run(&self, mut reader: Channel) {
+        loop {
+            let frame = match reader.recv::<Frame>() {
+                Ok(frame) => frame,
+                Err(e) => {
+                    info!("mux closed: {}", e);
+                    break;
+                }
+            };
+            let mut streams = self.streams();
+            // A stream the other side broke the protocol on, to reset.
+            let mut reset = None;
+            match frame {
+                Frame::Open((opened, n)) => {
+                    let id = (!opened, n);
+                    match streams.map.get_mut(&id) {
+                        Some(stream) => {
+                            error!("mux stream {:?} opened twice", id);
+                            stream.reset(ErrorKind::InvalidData, "mux stream opened twice");
+                            reset = Some(id);
+                        }
+                        None => {
+                            streams.map.insert(id, Stream::new());
+                            streams.incoming.push_back(id);
+                        }
+                    }
+                }
+                Frame::Data((opened, n), bytes) => {
+                    let id = (!opened, n);
+                    if let Some(stream) = streams.map.get_mut(&id) {
+                        if stream.reset.is_some() {
+                            // Whatever was in flight when it was reset.
+                        } else if stream.buffer.len() + bytes.len() > WINDOW {
+                            error!("mux stream {:?} overran its window", id);
+                            stream.reset(ErrorKind::InvalidData, "mux stream overran its window");
+                            reset = Some(id);
+                        } else {
+                            stream.buffer.extend(bytes);
+                        }
+                    }
+                }
+                Frame::Window((opened, n), credit) => {
+                    if let Some(stream) = streams.map.get_mut(&(!opened, n)) {
+                        stream.credit += credit as usize;
+                    }
+                }
+                Frame::Close((opened, n)) => {
+                    let id = (!opened, n);
+                    if let Some(stream) = streams.map.get_mut(&id) {
+                        stream.eof = true;
+                        if stream.closed && stream.buffer.is_empty() {
+                            streams.map.remove(&id);
+                        }
+                    }
+                }
+                Frame::Reset((opened, n)) => {
+                    if let Some(stream) = streams.map.get_mut(&(!opened, n)) {
+                        stream.reset(ErrorKind::ConnectionReset, "mux stream reset by peer");
+                    }
+                }
+            }
+            drop(streams);
+            self.changed.notify_all();
+            if let Some(id) = reset {
+                if let Err(e) = self.send(&Frame::Reset(id)) {
+                    info!("mux closed: {}", e);
+                    break;
+                }
+            }
+        }
+        let mut streams = self.streams();
+        streams.closed = true;
+        for stream in streams.map.values_mut() {
+            stream.eof = true;
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl Mux {
+    /// Multiplex over an established channel, from now on it should only be
+    /// used through the mux.
+    pub fn new(channel: Channel) -> Result<Mux, Error> {
+        let reader = Channel(channel.0.clone(), channel.1.try_clone()?, None, false);
+        let shared = Arc::new(Shared {
+            info: channel.0.clone(),
+            writer: Mutex::new(channel),
+            streams: Mutex::new(Streams::default()),
+            changed: Condvar::new(),
+        });
+        let s = shared.clone();
+        let reader = thread::spawn(move || s.run(reader));
+        Ok(Mux { shared, reader: Some(reader) })
+    }
+
+    /// Open a new stream, the other side gets it from `accept`.
+    pub fn open(&self) -> Result<Channel, Error> {
+        let id = {
+            let mut streams = self.shared.streams();
+            if streams.closed {
+                return Err(closed());
+            }
+            let id = (true, streams.next);
+            streams.next += 1;
+            streams.map.insert(id, Stream::new());
+            id
+        };
+        self.shared.send(&Frame::Open(id))?;
+        Ok(self.channel(id))
+    }
+
+    /// Wait for the other side to open a stream.
+    pub fn accept(&self) -> Result<Channel, Error> {
+        let mut streams = self.shared.streams();
+        loop {
+            if let Some(id) = streams.incoming.pop_front() {
+                return Ok(self.channel(id));
+            }
+            if streams.closed {
+                return Err(closed());
+            }
+            streams = self.shared.wait(streams);
+        }
+    }
+
+    fn channel(&self, id: Id) -> Channel {
+        let stream = MuxStream { shared: self.shared.clone(), id };
+        Channel(self.shared.info.clone(), Box::new(stream), None, false)
+    }
+}
+
+/// Dropping the mux closes the underlying channel, and with it every stream.
+impl Drop for Mux {
+    fn drop(&mut self) {
+        self.shared.writer.lock().unwrap().1.shutdown().ok();
+        if let Some(reader) = self.reader.take() {
+            reader.join().ok();
+        }
+    }
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "mux closed")
+}
+
+/// A stream's transport.
+struct MuxStream {
+    shared: Arc<Shared>,
+    id: Id,
+}
+
+impl Read for MuxStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut streams = self.shared.streams();
+        loop {
+            let stream = streams.map.get_mut(&self.id).ok_or_else(closed)?;
+            stream.check()?;
+            if !stream.buffer.is_empty() {
+                let n = buf.len().min(stream.buffer.len());
+                for (b, byte) in buf.iter_mut().zip(stream.buffer.drain(..n)) {
+                    *b = byte;
+                }
+                stream.consumed += n;
+                if stream.consumed < WINDOW / 2 || stream.eof {
+                    return Ok(n);
+                }
+                let credit = std::mem::take(&mut stream.consumed) as u32;
+                drop(streams);
+                self.shared.send(&Frame::Window(self.id, credit))?;
+                return Ok(n);
+            }
+            if stream.eof || stream.closed {
+                return Ok(0);
+            }
+            streams = self.shared.wait(streams);
+        }
+    }
+}
+
+impl Write for MuxStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut streams = self.shared.streams();
+        let n = loop {
+            let stream = streams.map.get_mut(&self.id).ok_or_else(closed)?;
+            stream.check()?;
+            if stream.eof || stream.closed {
+                return Err(Error::new(ErrorKind::BrokenPipe, "mux stream closed"));
+            }
+            if stream.credit > 0 {
+                let n = buf.len().min(stream.credit).min(MAX_FRAME);
+                stream.credit -= n;
+                break n;
+            }
+            streams = self.shared.wait(streams);
+        };
+        drop(streams);
+        self.shared.send(&Frame::Data(self.id, buf[..n].to_vec()))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for MuxStream {
+    fn is_ready(&self) -> Result<bool, Error> {
+        let streams = self.shared.streams();
+        match streams.map.get(&self.id) {
+            Some(stream) => Ok(!stream.buffer.is_empty() || stream.eof || stream.reset.is_some()),
+            None => Ok(true),
+        }
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.shared.writer.lock().unwrap().1.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.shared.writer.lock().unwrap().1.peer_addr()
+    }
+
+    /// Close this stream, leaving the rest open.
+    fn shutdown(&self) -> Result<(), Error> {
+        let mut streams = self.shared.streams();
+        let stream = match streams.map.get_mut(&self.id) {
+            Some(stream) if !stream.closed => stream,
+            _ => return Ok(()),
+        };
+        stream.closed = true;
+        stream.buffer.clear();
+        if stream.eof {
+            streams.map.remove(&self.id);
+        }
+        let closed = streams.closed;
+        self.shared.changed.notify_all();
+        drop(streams);
+        if closed {
+            Ok(())
+        } else {
+            self.shared.send(&Frame::Close(self.id))
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+        self.shared.streams().map.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (Mux, Mux) {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        (Mux::new(a).unwrap(), Mux::new(b).unwrap())
+    }
+
+    #[test]
+    fn open_accept() {
+        let (a, b) = pair();
+        let mut x = a.open().unwrap();
+        let mut y = b.open().unwrap();
+        x.send(&1u64).unwrap();
+        y.send(&"two".to_string()).unwrap();
+        let mut x2 = b.accept().unwrap();
+        let mut y2 = a.accept().unwrap();
+        assert_eq!("two", y2.recv::<String>().unwrap());
+        assert_eq!(1u64, x2.recv::<u64>().unwrap());
+        assert_eq!("nixpulvis", x2.info());
+    }
+
+    #[test]
+    fn concurrent_streams() {
+        let (a, b) = pair();
+        let b = Arc::new(b);
+        let servers = (0..10).map(|_| {
+            let b = b.clone();
+            thread::spawn(move || {
+                let mut c = b.accept().unwrap();
+                let n: u64 = c.recv().unwrap();
+                c.send(&(n * 2)).unwrap();
+            })
+        }).collect::<Vec<_>>();
+        let mut streams = (0..10).map(|_| a.open().unwrap()).collect::<Vec<_>>();
+        for (i, c) in streams.iter_mut().enumerate() {
+            c.send(&(i as u64)).unwrap();
+        }
+        for (i, c) in streams.iter_mut().enumerate().rev() {
+            assert_eq!(i as u64 * 2, c.recv::<u64>().unwrap());
+        }
+        for server in servers {
+            server.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn flow_control() {
+        let (a, b) = pair();
+        let mut slow = a.open().unwrap();
+        let mut fast = a.open().unwrap();
+        let mut slow2 = b.accept().unwrap();
+        let mut fast2 = b.accept().unwrap();
+
+        // Fill the slow stream's window, without it being read.
+        let t = thread::spawn(move || {
+            slow.send(&vec![0u8; WINDOW * 3]).unwrap();
+            slow
+        });
+        thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!t.is_finished());
+
+        // The other stream isn't held up.
+        fast.send(&7u8).unwrap();
+        assert_eq!(7u8, fast2.recv::<u8>().unwrap());
+
+        assert_eq!(WINDOW * 3, slow2.recv::<Vec<u8>>().unwrap().len());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn close_stream() {
+        let (a, b) = pair();
+        let x = a.open().unwrap();
+        let mut y = a.open().unwrap();
+        let mut x2 = b.accept().unwrap();
+        let mut y2 = b.accept().unwrap();
+        drop(x);
+        assert!(x2.recv::<u8>().is_err());
+        assert!(x2.send(&1u8).is_err());
+        y.send(&1u8).unwrap();
+        assert_eq!(1u8, y2.recv::<u8>().unwrap());
+    }
+
+    #[test]
+    fn reset_stream() {
+        let (a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let mux = Mux::new(a).unwrap();
+        let message = |n: u8| bincode::serialize(&(crate::Frame::Data, n)).unwrap();
+        for n in 0..3 {
+            b.send(&Frame::Open((true, n))).unwrap();
+        }
+        let mut x = mux.accept().unwrap();
+        let mut y = mux.accept().unwrap();
+        let mut z = mux.accept().unwrap();
+
+        // Opening a stream again resets just that stream.
+        b.send(&Frame::Open((true, 0))).unwrap();
+        assert_eq!(ErrorKind::InvalidData, x.recv::<u8>().unwrap_err().kind());
+        assert!(matches!(b.recv::<Frame>().unwrap(), Frame::Reset((false, 0))));
+
+        // So does overrunning its window.
+        b.send(&Frame::Data((true, 1), vec![0; WINDOW + 1])).unwrap();
+        assert_eq!(ErrorKind::InvalidData, y.recv::<u8>().unwrap_err().kind());
+        assert!(matches!(b.recv::<Frame>().unwrap(), Frame::Reset((false, 1))));
+        assert!(y.send(&1u8).is_err());
+
+        // The rest carry on.
+        b.send(&Frame::Data((true, 2), message(7))).unwrap();
+        assert_eq!(7, z.recv::<u8>().unwrap());
+        z.send(&8u8).unwrap();
+        assert!(matches!(b.recv::<Frame>().unwrap(), Frame::Data((false, 2), bytes) if bytes == message(8)));
+    }
+
+    #[test]
+    fn close_mux() {
+        let (a, b) = pair();
+        let _x = a.open().unwrap();
+        let mut x2 = b.accept().unwrap();
+        drop(a);
+        assert!(x2.recv::<u8>().is_err());
+        assert!(b.accept().is_err());
+        assert!(b.open().is_err());
+    }
+}
@@ -0,0 +1,629 @@
+//! Channels which survive their connection dropping.
+//!
+//! A `Reliable` transport numbers the frames it sends, and keeps each one
+//! until the other side acknowledges it. When the connection is lost, the
+//! client reconnects and presents the resumption token it was given when it
+//! first connected, and both sides send again whatever the other missed. The
+//! channel (and any session built on it) carries on as if nothing happened.
+//!
+//! ```rust,ignore
+//! // On the server.
+//! let listener = ReliableListener::bind("0.0.0.0:1337")?;
+//! let channel = Channel::accept_from_transport(listener.accept()?)?;
+//!
+//! // On the client.
+//! let channel = Channel::connect_to_transport(info, Reliable::connect(addr)?)?;
+//! ```
+use std::collections::{HashMap, VecDeque};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use log::{info, error};
+use rand::Rng;
+use rand::rngs::OsRng;
+use crate::transport::Transport;
+
+/// How many times a client tries to reconnect before giving up.
+pub const RECONNECT_ATTEMPTS: usize = 10;
+
+/// How long a client waits before its first reconnect, doubling each time.
+pub const RECONNECT_DELAY: Duration = Duration::from_millis(20);
+
+/// How long a server waits for a lost client to come back.
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many bytes a transport keeps waiting for acknowledgement before
+/// writes wait for some to be acknowledged, by default.
+pub const UNACKED_LIMIT: usize = 16 << 20;
+
+/// How long a write waits for an acknowledgement to make room, by default.
+pub const RETRANSMIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a new connection has to say hello.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the accepting thread sleeps between checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A secret which lets a client resume its session, drawn from the OS's
+/// random number generator.
+type Token = u128;
+
+/// The first message on every connection, from the client.
+#[derive(Serialize, Deserialize, Debug)]
+enum Hello {
+    New,
+    Resume { token: Token, next: u64 },
+}
+
+/// The server's reply to a `Hello`.
+#[derive(Serialize, Deserialize, Debug)]
+enum Welcome {
+    New { token: Token },
+    /// The next frame the server expects.
+    Resumed { next: u64 },
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Segment {
+    Data(u64, Vec<u8>),
+    /// Every frame before this one has arrived.
+    Ack(u64),
+    Close,
+}
+
+/// The connection currently under a reliable transport.
+#[derive(Default)]
+struct Link {
+    current: Mutex<Option<TcpStream>>,
+    /// A connection the client has resumed on, and the next frame it expects.
+    resumed: Mutex<Option<(TcpStream, u64)>>,
+    /// The next frame we expect, for the server to resume with.
+    next: AtomicU64,
+    ready: Condvar,
+}
+
+impl Link {
+    fn set(&self, stream: &TcpStream) {
+        *self.current.lock().unwrap() = stream.try_clone().ok();
+    }
+
+    fn interrupt(&self) {
+        if let Some(stream) = &*self.current.lock().unwrap() {
+            stream.shutdown(Shutdown::Both).ok();
+        }
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<Token, Arc<Link>>>>;
+
+enum Role {
+    Client { addr: SocketAddr },
+    Server { sessions: Sessions, timeout: Duration },
+}
+
+/// A transport which resumes after its connection drops, see the module
+/// documentation.
+pub struct Reliable {
+    stream: TcpStream,
+    role: Role,
+    link: Arc<Link>,
+    token: Token,
+    /// The number of the next frame we send.
+    sent: u64,
+    /// The number of the next frame we expect.
+    next: u64,
+    unacked: VecDeque<(u64, Vec<u8>)>,
+    /// The bytes in `unacked`, and how many there can be.
+    unacked_bytes: usize,
+    unacked_limit: usize,
+    retransmit_timeout: Duration,
+    pending: VecDeque<u8>,
+    eof: bool,
+    closed: AtomicBool,
+}
+
+/// A handle to a reliable transport's connection.
+#[derive(Clone)]
+pub struct Handle(Arc<Link>);
+
+impl Handle {
+    /// Drop the current connection, as if the network had failed.
+    pub fn interrupt(&self) {
+        self.0.interrupt();
+    }
+}
+
+fn write_message<W: Write, T: Serialize>(mut out: W, message: &T) -> Result<(), Error> {
+    let bytes = bincode::serialize(message).map_err(Error::other)?;
+    out.write_all(&bytes)
+}
+
+fn read_message<R: Read, T>(input: R) -> Result<T, Error>
+where for<'de> T: Deserialize<'de>
+{
+    bincode::deserialize_from(input).map_err(Error::other)
+}
+
+fn closed() -> Error {
+    Error::new(ErrorKind::BrokenPipe, "reliable transport closed")
+}
+
+impl Reliable {
+    /// Connect to a `ReliableListener`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Reliable, Error> {
+        let mut stream = TcpStream::connect(addr)?;
+        let addr = stream.peer_addr()?;
+        write_message(&mut stream, &Hello::New)?;
+        let token = match read_message(&mut stream)? {
+            Welcome::New { token } => token,
+            welcome => return Err(Error::other(format!("unexpected welcome: {:?}", welcome))),
+        };
+        info!("reliable connection to {:?}", addr);
+        Ok(Reliable::new(stream, Role::Client { addr }, Arc::default(), token))
+    }
+
+    fn new(stream: TcpStream, role: Role, link: Arc<Link>, token: Token) -> Reliable {
+        link.set(&stream);
+        Reliable {
+            stream,
+            role,
+            link,
+            token,
+            sent: 0,
+            next: 0,
+            unacked: VecDeque::new(),
+            unacked_bytes: 0,
+            unacked_limit: UNACKED_LIMIT,
+            retransmit_timeout: RETRANSMIT_TIMEOUT,
+            pending: VecDeque::new(),
+            eof: false,
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// How many bytes to keep waiting for the other side to acknowledge.
+    /// Writes wait once there would be more, until the other side catches
+    /// up.
+    pub fn unacked_limit(mut self, limit: usize) -> Reliable {
+        self.unacked_limit = limit;
+        self
+    }
+
+    /// How long a write waits for the other side to acknowledge anything,
+    /// once it's over the unacknowledged limit, before failing.
+    pub fn retransmit_timeout(mut self, timeout: Duration) -> Reliable {
+        self.retransmit_timeout = timeout;
+        self
+    }
+
+    /// A handle to this transport's connection, which can be used after the
+    /// transport has been moved into a channel.
+    pub fn handle(&self) -> Handle {
+        Handle(self.link.clone())
+    }
+
+    /// Get a new connection, and the next frame the other side expects.
+    fn reconnect(&mut self) -> Result<(TcpStream, u64), Error> {
+        match &self.role {
+            Role::Client { addr } => {
+                let mut result = Err(closed());
+                for attempt in 0..RECONNECT_ATTEMPTS {
+                    thread::sleep(RECONNECT_DELAY * (1 << attempt));
+                    info!("reconnecting to {:?}, attempt {}", addr, attempt + 1);
+                    result = TcpStream::connect(addr).and_then(|mut stream| {
+                        let hello = Hello::Resume { token: self.token, next: self.next };
+                        write_message(&mut stream, &hello)?;
+                        match read_message(&mut stream)? {
+                            Welcome::Resumed { next } => Ok((stream, next)),
+                            _ => Err(Error::new(ErrorKind::NotFound, "session expired")),
+                        }
+                    });
+                    match result {
+                        Err(ref e) if e.kind() == ErrorKind::NotFound => break,
+                        Err(_) => continue,
+                        Ok(_) => break,
+                    }
+                }
+                result
+            }
+            Role::Server { timeout, .. } => {
+                let resumed = self.link.resumed.lock().unwrap();
+                let (mut resumed, _) = self.link.ready
+                    .wait_timeout_while(resumed, *timeout, |r| r.is_none())
+                    .unwrap();
+                match resumed.take() {
+                    Some(resumed) => Ok(resumed),
+                    None => Err(Error::new(ErrorKind::TimedOut, "client didn't resume in time")),
+                }
+            }
+        }
+    }
+
+    /// Carry on over a new connection, sending again what the other side
+    /// missed.
+    fn resume(&mut self) -> Result<(), Error> {
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(closed());
+            }
+            info!("reliable connection lost, resuming");
+            let (stream, next) = self.reconnect().inspect_err(|_| self.close())?;
+            self.stream = stream;
+            self.link.set(&self.stream);
+            self.acknowledged(next);
+            let resent = self.unacked.iter().try_for_each(|(seq, bytes)| {
+                write_message(&self.stream, &Segment::Data(*seq, bytes.clone()))
+            });
+            if resent.is_ok() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn acknowledged(&mut self, next: u64) {
+        while self.unacked.front().is_some_and(|(seq, _)| *seq < next) {
+            if let Some((_, bytes)) = self.unacked.pop_front() {
+                self.unacked_bytes -= bytes.len();
+            }
+        }
+    }
+
+    /// Receive and handle one segment.
+    fn poll(&mut self) -> Result<(), Error> {
+        let segment = match read_message(&self.stream) {
+            Ok(segment) => segment,
+            Err(_) => return self.resume(),
+        };
+        match segment {
+            Segment::Data(seq, bytes) => {
+                if seq == self.next {
+                    self.next += 1;
+                    self.link.next.store(self.next, Ordering::SeqCst);
+                    self.pending.extend(bytes);
+                }
+                if write_message(&self.stream, &Segment::Ack(self.next)).is_err() {
+                    self.resume()?;
+                }
+            }
+            Segment::Ack(next) => self.acknowledged(next),
+            Segment::Close => {
+                info!("reliable connection closed by peer");
+                self.eof = true;
+                self.close();
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget the session, it can't be resumed anymore.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        if let Role::Server { sessions, .. } = &self.role {
+            sessions.lock().unwrap().remove(&self.token);
+        }
+    }
+}
+
+impl Read for Reliable {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.pending.is_empty() {
+            if self.eof {
+                return Ok(0);
+            }
+            self.poll()?;
+        }
+        let n = buf.len().min(self.pending.len());
+        for (b, byte) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *b = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for Reliable {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // Take in any waiting acknowledgements, so we don't keep frames longer
+        // than we need to.
+        while !self.eof && Transport::is_ready(&self.stream)? {
+            self.poll()?;
+        }
+        // Wait for room if the other side isn't keeping up, for as long as
+        // it keeps acknowledging something.
+        let mut since = (Instant::now(), self.unacked_bytes);
+        while !self.unacked.is_empty() && self.unacked_bytes + buf.len() > self.unacked_limit {
+            if self.closed.load(Ordering::SeqCst) || self.eof {
+                return Err(closed());
+            }
+            if self.unacked_bytes < since.1 {
+                since = (Instant::now(), self.unacked_bytes);
+            }
+            let remaining = self.retransmit_timeout.saturating_sub(since.0.elapsed());
+            if remaining.is_zero() {
+                return Err(Error::new(ErrorKind::TimedOut, "data wasn't acknowledged in time"));
+            }
+            if Transport::wait_ready(&self.stream, remaining)? {
+                self.poll()?;
+            }
+        }
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(closed());
+        }
+        let seq = self.sent;
+        self.sent += 1;
+        self.unacked_bytes += buf.len();
+        self.unacked.push_back((seq, buf.to_vec()));
+        if write_message(&self.stream, &Segment::Data(seq, buf.to_vec())).is_err() {
+            self.resume()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for Reliable {
+    /// This may be true when only an acknowledgement is waiting, in which
+    /// case a read would still block.
+    fn is_ready(&self) -> Result<bool, Error> {
+        Ok(!self.pending.is_empty() || self.eof || Transport::is_ready(&self.stream)?)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        self.stream.local_addr()
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        self.stream.peer_addr()
+    }
+
+    /// Close the session for good.
+    fn shutdown(&self) -> Result<(), Error> {
+        if !self.closed.load(Ordering::SeqCst) {
+            self.close();
+            write_message(&self.stream, &Segment::Close).ok();
+        }
+        self.stream.shutdown(Shutdown::Both)
+    }
+}
+
+impl Drop for Reliable {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+/// Accepts reliable connections, and hands resumed connections to their
+/// sessions.
+pub struct ReliableListener {
+    incoming: Receiver<(TcpStream, Token, Arc<Link>)>,
+    sessions: Sessions,
+    timeout: Duration,
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    acceptor: Option<JoinHandle<()>>,
+}
+
+impl ReliableListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<ReliableListener, Error> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        info!("reliable listener on: {:?}", local_addr);
+        let (tx, incoming) = mpsc::channel();
+        let sessions = Sessions::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        let acceptor = {
+            let (sessions, stop) = (sessions.clone(), stop.clone());
+            thread::spawn(move || {
+                while !stop.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            // Greet it on its own thread, so a slow client
+                            // doesn't hold up the others.
+                            let (sessions, tx) = (sessions.clone(), tx.clone());
+                            thread::spawn(move || {
+                                if let Err(e) = welcome(stream, &sessions, &tx) {
+                                    error!("reliable handshake failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(POLL_INTERVAL);
+                        }
+                        Err(e) => error!("accept error: {}", e),
+                    }
+                }
+            })
+        };
+        Ok(ReliableListener {
+            incoming,
+            sessions,
+            timeout: RESUME_TIMEOUT,
+            local_addr,
+            stop,
+            acceptor: Some(acceptor),
+        })
+    }
+
+    /// How long to wait for a lost client to come back, before ending its
+    /// session.
+    pub fn resume_timeout(mut self, timeout: Duration) -> ReliableListener {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Wait for a new client.
+    pub fn accept(&self) -> Result<Reliable, Error> {
+        let (stream, token, link) = self.incoming.recv().map_err(|_| closed())?;
+        let role = Role::Server { sessions: self.sessions.clone(), timeout: self.timeout };
+        Ok(Reliable::new(stream, role, link, token))
+    }
+}
+
+/// Greet a new connection, starting or resuming a session.
+fn welcome(mut stream: TcpStream,
+           sessions: &Sessions,
+           tx: &Sender<(TcpStream, Token, Arc<Link>)>) -> Result<(), Error>
+{
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(HELLO_TIMEOUT))?;
+    let hello = read_message(&mut stream)?;
+    stream.set_read_timeout(None)?;
+    match hello {
+        Hello::New => {
+            let token = OsRng.gen();
+            let link = Arc::<Link>::default();
+            sessions.lock().unwrap().insert(token, link.clone());
+            write_message(&mut stream, &Welcome::New { token })?;
+            info!("new reliable session: {:?}", stream.peer_addr());
+            tx.send((stream, token, link)).map_err(|_| closed())
+        }
+        Hello::Resume { token, next } => {
+            let link = sessions.lock().unwrap().get(&token).cloned();
+            match link {
+                Some(link) => {
+                    info!("resuming reliable session: {:?}", stream.peer_addr());
+                    let resumed = Welcome::Resumed { next: link.next.load(Ordering::SeqCst) };
+                    write_message(&mut stream, &resumed)?;
+                    link.interrupt();
+                    *link.resumed.lock().unwrap() = Some((stream, next));
+                    link.ready.notify_all();
+                    Ok(())
+                }
+                None => write_message(&mut stream, &Welcome::Unknown),
+            }
+        }
+    }
+}
+
+impl Drop for ReliableListener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(acceptor) = self.acceptor.take() {
+            acceptor.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Channel;
+    use super::*;
+
+    fn connect(listener: &ReliableListener) -> (Channel, Handle, Channel, Handle) {
+        let client = Reliable::connect(listener.local_addr()).unwrap();
+        let server = listener.accept().unwrap();
+        let (ch, sh) = (client.handle(), server.handle());
+        let t = thread::spawn(move || Channel::accept_from_transport(server).unwrap());
+        let c = Channel::connect_to_transport("nixpulvis".into(), client).unwrap();
+        (c, ch, t.join().unwrap(), sh)
+    }
+
+    #[test]
+    fn resume() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap();
+        let (mut c, ch, mut s, sh) = connect(&listener);
+        let t = thread::spawn(move || {
+            for i in 0..10u64 {
+                if i == 5 {
+                    sh.interrupt();
+                }
+                let n: u64 = s.recv().unwrap();
+                s.send(&(n + 1)).unwrap();
+            }
+        });
+        for i in 0..10u64 {
+            if i % 3 == 0 {
+                ch.interrupt();
+            }
+            c.send(&i).unwrap();
+            assert_eq!(i + 1, c.recv::<u64>().unwrap());
+        }
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn resend_unacknowledged() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap();
+        let (mut c, ch, mut s, _) = connect(&listener);
+        // Everything sent while the link is down arrives once it's back.
+        ch.interrupt();
+        for i in 0..5u64 {
+            c.send(&i).unwrap();
+        }
+        for i in 0..5u64 {
+            assert_eq!(i, s.recv::<u64>().unwrap());
+        }
+    }
+
+    #[test]
+    fn expired() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap()
+            .resume_timeout(Duration::from_millis(10));
+        let (mut c, _, mut s, sh) = connect(&listener);
+        sh.interrupt();
+        let error = s.recv::<u64>().unwrap_err();
+        assert!(error.to_string().contains("didn't resume in time"));
+        assert_eq!(ErrorKind::NotFound, c.send(&1u64).unwrap_err().kind());
+    }
+
+    #[test]
+    fn unacked_limit() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap();
+        let mut client = Reliable::connect(listener.local_addr()).unwrap()
+            .unacked_limit(1024)
+            .retransmit_timeout(Duration::from_millis(50));
+        let mut server = listener.accept().unwrap();
+
+        // Writes over the limit wait for the server to catch up.
+        let t = thread::spawn(move || {
+            let mut buf = [0; 3000];
+            thread::sleep(Duration::from_millis(20));
+            server.read_exact(&mut buf).unwrap();
+            server
+        });
+        for _ in 0..3 {
+            client.write_all(&[0; 1000]).unwrap();
+        }
+        let _server = t.join().unwrap();
+
+        // And fail if it never does.
+        client.write_all(&[0; 1000]).unwrap();
+        let error = client.write_all(&[0; 100]).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+    }
+
+    #[test]
+    fn silent_client() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap();
+        let _silent = TcpStream::connect(listener.local_addr()).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        let (mut c, _, mut s, _) = connect(&listener);
+        assert!(start.elapsed() < HELLO_TIMEOUT);
+        c.send(&1u64).unwrap();
+        assert_eq!(1, s.recv::<u64>().unwrap());
+    }
+
+    #[test]
+    fn close() {
+        let listener = ReliableListener::bind("127.0.0.1:0").unwrap();
+        let (c, _, mut s, _) = connect(&listener);
+        drop(c);
+        assert!(s.recv::<u64>().is_err());
+        assert!(s.send(&1u64).is_err());
+    }
+}
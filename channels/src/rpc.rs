@@ -1,7 +1,47 @@
-use std::fmt::Debug;
-use std::io::Error;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{self, Debug, Display};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use serde::{Serialize, Deserialize};
-use super::Channel;
+use log::info;
+use super::{Channel, Frame};
+
+/// One message of a stream of values.
+#[derive(Serialize, Deserialize, Debug)]
+enum Item<T, E> {
+    Next(T),
+    End,
+    /// The stream stopped early, with this error.
+    Failed(E),
+}
+
+/// The error a remote handler returned from a call, see `Channel::try_call`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteError<E>(pub E);
+
+impl<E: Debug + Display + 'static> RemoteError<E> {
+    /// The remote error inside an error returned by `try_call`, if it was
+    /// one, and not a failure of the channel itself.
+    pub fn downcast(error: &Error) -> Option<&E> {
+        error.get_ref()
+            .and_then(|e| e.downcast_ref::<RemoteError<E>>())
+            .map(|e| &e.0)
+    }
+}
+
+impl<E: Display> Display for RemoteError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "remote error: {}", self.0)
+    }
+}
+
+impl<E: Debug + Display> error::Error for RemoteError<E> {}
 
 /// Cannel RPC interface.
 ///
@@ -24,12 +64,532 @@ impl Channel {
               C: Serialize + Debug,
     {
         let domain = self.recv()?;
-        // NOTE: We could make this check the `Result` of the call by changing
-        // the type of this function to `&Fn(&D) -> Result<C, ...>`.
         let codomain = (func)(&domain);
         self.send(&codomain)?;
         Ok(domain)
     }
+
+    /// Call a fallible function, see `accept_try_call`. An error from the
+    /// function comes back as a `RemoteError<E>`, which `RemoteError::downcast`
+    /// tells apart from errors of the channel itself.
+    pub fn try_call<D, C, E>(&mut self, domain: &D) -> Result<C, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        self.send(&domain)?;
+        match self.recv::<Result<C, E>>()? {
+            Ok(codomain) => Ok(codomain),
+            Err(e) => Err(Error::other(RemoteError(e))),
+        }
+    }
+
+    /// Like `accept_call`, but for a function which can fail, in which case
+    /// its error is sent back to the caller, and returned here too.
+    pub fn accept_try_call<D, C, E>(&mut self, func: &dyn Fn(&D) -> Result<C, E>) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de> + Debug,
+              C: Serialize + Debug,
+              E: Serialize + Debug + Display,
+    {
+        let domain = self.recv()?;
+        let codomain = (func)(&domain);
+        self.send(&codomain)?;
+        match codomain {
+            Ok(_) => Ok(domain),
+            Err(e) => Err(Error::other(format!("call failed: {}", e))),
+        }
+    }
+}
+
+/// Streaming RPC.
+///
+/// `call_stream` -> `accept_call_stream` for functions which return many
+/// values, which the caller iterates over as they arrive, and
+/// `call_with_stream` -> `accept_call_with_stream` for functions which take
+/// many values.
+impl Channel {
+    pub fn call_stream<D, C>(&mut self, domain: &D) -> Result<Stream<'_, C>, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+    {
+        self.try_call_stream(domain)
+    }
+
+    pub fn accept_call_stream<D, C, I>(&mut self, func: &dyn Fn(&D) -> I) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de> + Debug,
+              C: Serialize + Debug,
+              I: IntoIterator<Item = C>,
+    {
+        self.accept_try_call_stream(&|d| func(d).into_iter().map(Ok::<C, String>))
+    }
+
+    /// Call a function returning a stream which can fail part way through,
+    /// in which case the stream's last item is a `RemoteError<E>`.
+    pub fn try_call_stream<D, C, E>(&mut self, domain: &D) -> Result<Stream<'_, C, E>, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        self.send(&domain)?;
+        Ok(Stream { channel: self, done: false, types: PhantomData })
+    }
+
+    /// Like `accept_call_stream`, but the stream stops at the first error,
+    /// which is sent back to the caller, and returned here too.
+    pub fn accept_try_call_stream<D, C, E, I>(&mut self, func: &dyn Fn(&D) -> I) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de> + Debug,
+              C: Serialize + Debug,
+              E: Serialize + Debug + Display,
+              I: IntoIterator<Item = Result<C, E>>,
+    {
+        let domain = self.recv()?;
+        for codomain in func(&domain) {
+            match codomain {
+                Ok(c) => self.send(&Item::<C, E>::Next(c))?,
+                Err(e) => {
+                    self.send(&Item::<C, &E>::Failed(&e))?;
+                    return Err(Error::other(format!("call failed: {}", e)));
+                }
+            }
+        }
+        self.send(&Item::<C, E>::End)?;
+        Ok(domain)
+    }
+
+    pub fn call_with_stream<D, C, I>(&mut self, domain: I) -> Result<C, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+              I: IntoIterator<Item = D>,
+    {
+        for d in domain {
+            self.send(&Item::<D, ()>::Next(d))?;
+        }
+        self.send(&Item::<D, ()>::End)?;
+        self.recv()
+    }
+
+    /// Pass the caller's values to `func` as they arrive. If the channel
+    /// fails part way, `func` sees the values so far, and the error is
+    /// returned instead of a reply being sent.
+    pub fn accept_call_with_stream<D, C>(&mut self, func: &dyn Fn(&mut Incoming<'_, D>) -> C) -> Result<(), Error>
+        where D: for<'de> Deserialize<'de> + Debug,
+              C: Serialize + Debug,
+    {
+        let mut incoming = Incoming { channel: self, done: false, error: None, types: PhantomData };
+        let codomain = func(&mut incoming);
+        incoming.by_ref().for_each(drop);
+        match incoming.error {
+            Some(e) => Err(e),
+            None => self.send(&codomain),
+        }
+    }
+}
+
+/// How long a caller will wait for a call, and whether it's given up.
+///
+/// The caller's deadline is sent along with the call, so the handler can stop
+/// early once nobody is waiting for the answer. Either side can `cancel` it,
+/// from any thread.
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    expires: Instant,
+    cancelled: Arc<AtomicBool>,
+    /// For a handler's deadline, the call's id and the caller, who's read
+    /// from for a cancel.
+    caller: Option<(u64, Arc<Mutex<Channel>>)>,
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            expires: Instant::now() + timeout,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            caller: None,
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.expires.saturating_duration_since(Instant::now())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.expires
+    }
+
+    /// Give up on the call.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if let Some((id, caller)) = &self.caller {
+            let mut caller = caller.lock().unwrap();
+            // Until it has the answer, the caller only sends a cancel.
+            while !self.cancelled.load(Ordering::SeqCst) && caller.is_ready().unwrap_or(true) {
+                match caller.recv_control() {
+                    Ok(Frame::Cancel(i)) if i != *id => info!("skipping cancel of call {}", i),
+                    _ => self.cancel(),
+                }
+            }
+        }
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Is the caller still waiting for an answer?
+    pub fn is_waiting(&self) -> bool {
+        !self.is_expired() && !self.is_cancelled()
+    }
+}
+
+/// How often a waiting caller checks whether its deadline was cancelled.
+const CANCEL_CHECK: Duration = Duration::from_millis(10);
+
+/// RPC with deadlines.
+///
+/// `call_with_deadline` -> `accept_call_with_deadline` work like `call` ->
+/// `accept_call`, except the caller stops waiting as soon as the deadline
+/// passes or it's cancelled, and the handler is told. Each call has an id,
+/// which its answer is sent with, so an answer which comes too late is
+/// skipped over by whatever the caller receives next.
+impl Channel {
+    /// Call a function, failing with `ErrorKind::TimedOut` if there's no
+    /// answer before the deadline, or it's cancelled first.
+    pub fn call_with_deadline<D, C>(&mut self, domain: &D, deadline: &Deadline) -> Result<C, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+    {
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+        let id = CALLS.fetch_add(1, Ordering::SeqCst);
+        self.send(&(id, domain, deadline.remaining()))?;
+        loop {
+            if !deadline.is_waiting() {
+                self.send_control(&Frame::Cancel(id))?;
+                return Err(Error::new(ErrorKind::TimedOut, "call deadline passed"));
+            }
+            if !self.wait_ready(deadline.remaining().min(CANCEL_CHECK))? {
+                continue;
+            }
+            match self.recv_control()? {
+                Frame::Answer(i, Some(bytes)) if i == id => {
+                    return bincode::deserialize(&bytes).map_err(Error::other);
+                }
+                Frame::Answer(i, None) if i == id => {
+                    return Err(Error::new(ErrorKind::TimedOut, "call deadline passed"));
+                }
+                Frame::Data => {
+                    return Err(Error::new(ErrorKind::InvalidData, "expected an answer, got a message"));
+                }
+                Frame::Close => return Err(crate::peer_closed()),
+                stale => info!("skipping {:?} {:?}", stale, self),
+            }
+        }
+    }
+
+    /// Like `accept_call`, but `func` is given the caller's deadline, which
+    /// is cancelled if the caller gives up. If the caller's stopped waiting
+    /// by the time `func` returns, the answer is discarded and this fails
+    /// with `ErrorKind::Interrupted`.
+    ///
+    /// Checking the deadline reads the caller's cancel from a clone of the
+    /// transport, so this fails if it can't be cloned, and the deadline
+    /// mustn't be checked once `func` has returned.
+    pub fn accept_call_with_deadline<D, C>(&mut self, func: &dyn Fn(&D, &Deadline) -> C) -> Result<D, Error>
+        where D: for<'de> Deserialize<'de> + Debug,
+              C: Serialize + Debug,
+    {
+        let caller = Channel(self.0.clone(), self.1.try_clone()?, self.2.clone(), false);
+        let (id, domain, timeout) = self.recv::<(u64, D, Duration)>()?;
+        let mut deadline = Deadline::after(timeout);
+        deadline.caller = Some((id, Arc::new(Mutex::new(caller))));
+        let codomain = func(&domain, &deadline);
+        if deadline.is_waiting() {
+            let bytes = bincode::serialize(&codomain).map_err(Error::other)?;
+            self.send_control(&Frame::Answer(id, Some(bytes)))?;
+            Ok(domain)
+        } else {
+            self.send_control(&Frame::Answer(id, None))?;
+            Err(Error::new(ErrorKind::Interrupted, "call cancelled"))
+        }
+    }
+}
+
+/// Identifies a call, so a retry of it can be told apart from a new call.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Key(pub u128);
+
+impl Key {
+    /// A new random key, unique to this call.
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Key {
+        Key(rand::random())
+    }
+}
+
+/// Results of recent calls by client and key, see
+/// `Channel::accept_call_once`. One `Results` can be shared by every channel
+/// a call might be retried on, clients are told apart by the channel's info,
+/// so one can't be given another's results.
+pub struct Results {
+    entries: Mutex<HashMap<(Option<String>, Key), Entry>>,
+    finished: Condvar,
+    ttl: Duration,
+    capacity: usize,
+}
+
+/// A call's entry, with its serialized arguments, so a key can't be reused
+/// for a different call.
+enum Entry {
+    /// The call's being made right now.
+    Running(Vec<u8>),
+    Done(Instant, Vec<u8>, Vec<u8>),
+}
+
+impl Default for Results {
+    fn default() -> Results {
+        Results::new(Duration::from_secs(600), 1024)
+    }
+}
+
+impl Results {
+    /// Remember results for `ttl`, and no more than `capacity` of them.
+    pub fn new(ttl: Duration, capacity: usize) -> Results {
+        Results { entries: Mutex::new(HashMap::new()), finished: Condvar::new(), ttl, capacity }
+    }
+
+    /// The result of an earlier call with this key, or else a guard to
+    /// record the result of this one. If the earlier call is still running
+    /// this waits for it. This fails with `ErrorKind::InvalidInput` if the
+    /// earlier call's arguments were different.
+    fn begin(&self, client: Option<String>, key: Key, args: Vec<u8>) -> Result<Result<Vec<u8>, Running<'_>>, Error> {
+        let id = (client, key);
+        let mut entries = self.entries.lock().unwrap();
+        loop {
+            entries.retain(|_, entry| match entry {
+                Entry::Running(_) => true,
+                Entry::Done(at, ..) => at.elapsed() < self.ttl,
+            });
+            match entries.get(&id) {
+                Some(Entry::Running(earlier)) | Some(Entry::Done(_, earlier, _)) if *earlier != args => {
+                    return Err(Error::new(ErrorKind::InvalidInput, "call key reused with different arguments"));
+                }
+                Some(Entry::Done(_, _, bytes)) => return Ok(Ok(bytes.clone())),
+                Some(Entry::Running(_)) => entries = self.finished.wait(entries).unwrap(),
+                None => {
+                    entries.insert(id.clone(), Entry::Running(args.clone()));
+                    return Ok(Err(Running { results: self, id, args }));
+                }
+            }
+        }
+    }
+
+    /// Call `func` unless this call was made already, in which case its
+    /// result is given again.
+    fn once<D, C>(&self, client: &Option<String>, key: Key, domain: &D, func: impl FnOnce(&D) -> C) -> Result<C, Error>
+        where D: Serialize,
+              C: Serialize + for<'de> Deserialize<'de>,
+    {
+        let args = bincode::serialize(domain).map_err(Error::other)?;
+        match self.begin(client.clone(), key, args)? {
+            Ok(bytes) => {
+                info!("replaying result of call {:?}", key);
+                bincode::deserialize(&bytes).map_err(Error::other)
+            }
+            Err(running) => {
+                let codomain = func(domain);
+                running.finish(bincode::serialize(&codomain).map_err(Error::other)?);
+                Ok(codomain)
+            }
+        }
+    }
+}
+
+/// A call which is running, if it doesn't finish, it's forgotten so a retry
+/// can run it again.
+struct Running<'a> {
+    results: &'a Results,
+    id: (Option<String>, Key),
+    args: Vec<u8>,
+}
+
+impl Running<'_> {
+    fn finish(mut self, bytes: Vec<u8>) {
+        let mut entries = self.results.entries.lock().unwrap();
+        let args = mem::take(&mut self.args);
+        entries.insert(self.id.clone(), Entry::Done(Instant::now(), args, bytes));
+        while entries.len() > self.results.capacity {
+            let oldest = entries.iter()
+                .filter_map(|(id, entry)| match entry {
+                    Entry::Done(at, ..) => Some((*at, id.clone())),
+                    Entry::Running(_) => None,
+                })
+                .min();
+            match oldest {
+                Some((_, id)) => entries.remove(&id),
+                None => break,
+            };
+        }
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.results.entries.lock().unwrap();
+        if let Some(Entry::Running(_)) = entries.get(&self.id) {
+            entries.remove(&self.id);
+        }
+        self.results.finished.notify_all();
+    }
+}
+
+/// Idempotent RPC.
+///
+/// `call_once` -> `accept_call_once` send a `Key` with the call, and the
+/// result is kept by the key, so a retry of the call, maybe on a new channel
+/// after a timeout, gets the original result instead of running the function
+/// again. A key can only be reused with the same arguments.
+impl Channel {
+    /// Call a function, failing with `ErrorKind::InvalidInput` if `key` was
+    /// already used for a call with different arguments.
+    pub fn call_once<D, C>(&mut self, key: Key, domain: &D) -> Result<C, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+    {
+        self.send(&(key, domain))?;
+        self.recv::<Result<C, String>>()?
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    /// Like `accept_call`, but `func` is only called once for each key
+    /// remembered in `results`.
+    pub fn accept_call_once<D, C>(&mut self, results: &Results, func: &dyn Fn(&D) -> C) -> Result<D, Error>
+        where D: Serialize + for<'de> Deserialize<'de> + Debug,
+              C: Serialize + for<'de> Deserialize<'de> + Debug,
+    {
+        let (key, domain) = self.recv::<(Key, D)>()?;
+        match results.once(&self.0, key, &domain, func) {
+            Ok(codomain) => {
+                self.send(&Ok::<C, String>(codomain))?;
+                Ok(domain)
+            }
+            Err(e) => {
+                self.send(&Err::<C, String>(e.to_string()))?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Like `call_once`, but giving up at the deadline, like
+    /// `call_with_deadline`. The call can then be retried with the same key.
+    pub fn call_once_with_deadline<D, C>(&mut self, key: Key, domain: &D, deadline: &Deadline) -> Result<C, Error>
+        where D: Serialize + Debug,
+              C: for<'de> Deserialize<'de> + Debug,
+    {
+        self.call_with_deadline::<_, Result<C, String>>(&(key, domain), deadline)?
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    }
+
+    /// Like `accept_call_once`, but `func` is given the caller's deadline,
+    /// see `accept_call_with_deadline`. A result is remembered even if the
+    /// caller stopped waiting for it, so its retry gets it.
+    pub fn accept_call_once_with_deadline<D, C>(&mut self, results: &Results, func: &dyn Fn(&D, &Deadline) -> C) -> Result<D, Error>
+        where D: Serialize + for<'de> Deserialize<'de> + Debug,
+              C: Serialize + for<'de> Deserialize<'de> + Debug,
+    {
+        let client = self.0.clone();
+        let failed = RefCell::new(None);
+        let (_, domain) = self.accept_call_with_deadline(&|(key, domain): &(Key, D), deadline: &Deadline| {
+            results.once(&client, *key, domain, |domain| func(domain, deadline)).map_err(|e| {
+                let message = e.to_string();
+                *failed.borrow_mut() = Some(e);
+                message
+            })
+        })?;
+        match failed.into_inner() {
+            Some(e) => Err(e),
+            None => Ok(domain),
+        }
+    }
+}
+
+/// The values returned by a streaming call, see `Channel::call_stream`.
+///
+/// Dropping a stream before the end reads the rest of it, so the channel is
+/// ready for the next message.
+pub struct Stream<'a, C, E = String>
+    where C: for<'de> Deserialize<'de> + Debug,
+          E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+{
+    channel: &'a mut Channel,
+    done: bool,
+    types: PhantomData<fn() -> (C, E)>,
+}
+
+impl<C, E> Iterator for Stream<'_, C, E>
+    where C: for<'de> Deserialize<'de> + Debug,
+          E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+{
+    type Item = Result<C, Error>;
+
+    fn next(&mut self) -> Option<Result<C, Error>> {
+        if self.done {
+            return None;
+        }
+        match self.channel.recv::<Item<C, E>>() {
+            Ok(Item::Next(c)) => Some(Ok(c)),
+            Ok(Item::End) => {
+                self.done = true;
+                None
+            }
+            Ok(Item::Failed(e)) => {
+                self.done = true;
+                Some(Err(Error::other(RemoteError(e))))
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<C, E> Drop for Stream<'_, C, E>
+    where C: for<'de> Deserialize<'de> + Debug,
+          E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+{
+    fn drop(&mut self) {
+        self.for_each(drop);
+    }
+}
+
+/// The values passed to a streaming call, see
+/// `Channel::accept_call_with_stream`.
+pub struct Incoming<'a, D> {
+    channel: &'a mut Channel,
+    done: bool,
+    error: Option<Error>,
+    types: PhantomData<fn() -> D>,
+}
+
+impl<D> Iterator for Incoming<'_, D>
+    where D: for<'de> Deserialize<'de> + Debug,
+{
+    type Item = D;
+
+    fn next(&mut self) -> Option<D> {
+        if self.done {
+            return None;
+        }
+        match self.channel.recv::<Item<D, ()>>() {
+            Ok(Item::Next(d)) => return Some(d),
+            Ok(Item::End) => {}
+            Ok(Item::Failed(())) => {
+                self.error = Some(Error::other("caller's stream failed"));
+            }
+            Err(e) => self.error = Some(e),
+        }
+        self.done = true;
+        None
+    }
 }
 
 #[cfg(test)]
@@ -48,10 +608,267 @@ mod tests {
         thread::sleep(Duration::from_millis(10));
         thread::spawn(move || {
             let mut connect_channel = Channel::connect_to_socket_addr("nixpulvis".into(), "127.0.0.1:1337").unwrap();
-            assert_eq!(false, connect_channel.call(&true).unwrap());
+            assert!(!connect_channel.call::<_, bool>(&true).unwrap());
         }).join().unwrap();
     }
 
+    #[test]
+    fn try_call() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            let divide = |(n, d): &(u64, u64)| n.checked_div(*d).ok_or("divide by zero");
+            assert_eq!((6, 3), b.accept_try_call(&divide).unwrap());
+            assert!(b.accept_try_call(&divide).is_err());
+        });
+        assert_eq!(2u64, a.try_call::<_, u64, String>(&(6u64, 3u64)).unwrap());
+        let error = a.try_call::<_, u64, String>(&(1u64, 0u64)).unwrap_err();
+        assert_eq!(Some(&"divide by zero".to_string()), RemoteError::<String>::downcast(&error));
+        t.join().unwrap();
+
+        // Errors of the channel itself aren't remote errors.
+        let error = a.try_call::<_, u64, String>(&(1u64, 1u64)).unwrap_err();
+        assert_eq!(None, RemoteError::<String>::downcast(&error));
+    }
+
+    #[test]
+    fn streams() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            assert_eq!(1000, b.accept_call_stream(&|n: &u64| 0..*n).unwrap());
+            b.accept_call_stream(&|n: &u64| 0..*n).unwrap();
+            b.accept_call_with_stream(&|xs: &mut Incoming<u64>| xs.sum::<u64>()).unwrap();
+            let countdown = |n: &u64| (0..=*n).rev().map(|i| i.checked_sub(1).ok_or("liftoff"));
+            assert!(b.accept_try_call_stream(&countdown).is_err());
+        });
+        let items = a.call_stream::<_, u64>(&1000u64).unwrap().collect::<Result<Vec<_>, _>>();
+        assert_eq!((0..1000).collect::<Vec<_>>(), items.unwrap());
+
+        // Stopping early leaves the channel ready for the next call.
+        assert_eq!(Some(0), a.call_stream::<_, u64>(&10u64).unwrap().next().map(Result::unwrap));
+        assert_eq!(6, a.call_with_stream::<_, u64, _>(vec![1u64, 2, 3]).unwrap());
+
+        let mut stream = a.try_call_stream::<_, u64, String>(&2u64).unwrap();
+        assert_eq!(1, stream.next().unwrap().unwrap());
+        assert_eq!(0, stream.next().unwrap().unwrap());
+        let error = stream.next().unwrap().unwrap_err();
+        assert_eq!(Some(&"liftoff".to_string()), RemoteError::<String>::downcast(&error));
+        assert!(stream.next().is_none());
+        drop(stream);
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn stream_hang_up() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            b.send(&Item::<u64, ()>::Next(1)).unwrap();
+        });
+        let seen = std::sync::Mutex::new(vec![]);
+        let result = a.accept_call_with_stream(&|xs: &mut Incoming<u64>| {
+            seen.lock().unwrap().extend(xs);
+        });
+        assert!(result.is_err());
+        assert_eq!(vec![1], seen.into_inner().unwrap());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn deadline() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            let wait = |ms: &u64, deadline: &Deadline| {
+                let until = Instant::now() + Duration::from_millis(*ms);
+                while deadline.is_waiting() && Instant::now() < until {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                deadline.is_waiting()
+            };
+            assert_eq!(5, b.accept_call_with_deadline(&wait).unwrap());
+            let error = b.accept_call_with_deadline(&wait).unwrap_err();
+            assert_eq!(ErrorKind::Interrupted, error.kind());
+            let error = b.accept_call_with_deadline(&wait).unwrap_err();
+            assert_eq!(ErrorKind::Interrupted, error.kind());
+            assert_eq!(1, b.accept_call_with_deadline(&wait).unwrap());
+        });
+        let deadline = Deadline::after(Duration::from_secs(10));
+        assert!(a.call_with_deadline::<_, bool>(&5u64, &deadline).unwrap());
+
+        // The handler stops as soon as the caller gives up.
+        let start = Instant::now();
+        let deadline = Deadline::after(Duration::from_millis(20));
+        let error = a.call_with_deadline::<_, bool>(&10_000u64, &deadline).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Also when it's cancelled before the deadline.
+        let deadline = Deadline::after(Duration::from_secs(10));
+        let d = deadline.clone();
+        let canceller = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            d.cancel();
+        });
+        let error = a.call_with_deadline::<_, bool>(&10_000u64, &deadline).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        canceller.join().unwrap();
+
+        // And the channel's still in step.
+        let deadline = Deadline::after(Duration::from_secs(10));
+        assert!(a.call_with_deadline::<_, bool>(&1u64, &deadline).unwrap());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn deadline_ignored() {
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || {
+            let stubborn = |ms: &u64, _: &Deadline| thread::sleep(Duration::from_millis(*ms));
+            assert!(b.accept_call_with_deadline(&stubborn).is_err());
+            assert!(b.accept_call_with_deadline(&stubborn).is_err());
+            b.accept_call(&|n: &u64| n + 1).unwrap();
+        });
+
+        // The caller doesn't wait for a handler which won't stop, however
+        // many times it's called.
+        for _ in 0..2 {
+            let start = Instant::now();
+            let deadline = Deadline::after(Duration::from_millis(50));
+            let error = a.call_with_deadline::<_, ()>(&200u64, &deadline).unwrap_err();
+            assert_eq!(ErrorKind::TimedOut, error.kind());
+            assert!(start.elapsed() < Duration::from_millis(150));
+        }
+
+        // The late answers are skipped over by the next call.
+        assert_eq!(2, a.call::<_, u64>(&1u64).unwrap());
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn call_once() {
+        use std::sync::atomic::AtomicUsize;
+
+        let results = Arc::new(Results::default());
+        let admitted = Arc::new(AtomicUsize::new(0));
+        let admit = {
+            let admitted = admitted.clone();
+            move |id: &u64| {
+                admitted.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(20));
+                id * 100
+            }
+        };
+        let serve = |mut channel: Channel| {
+            let (results, admit) = (results.clone(), admit.clone());
+            thread::spawn(move || channel.accept_call_once(&results, &admit))
+        };
+
+        // The reply to the first try is lost.
+        let key = Key::new();
+        let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = serve(b);
+        a.send(&(key, 3u64)).unwrap();
+        drop(a);
+        t.join().unwrap().ok();
+        assert_eq!(1, admitted.load(Ordering::SeqCst));
+
+        // Two retries at once, on new channels.
+        let retries = (0..2).map(|_| {
+            let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+            let t = serve(b);
+            thread::spawn(move || {
+                let expire = a.call_once::<_, u64>(key, &3u64).unwrap();
+                t.join().unwrap().unwrap();
+                expire
+            })
+        }).collect::<Vec<_>>();
+        for retry in retries {
+            assert_eq!(300, retry.join().unwrap());
+        }
+        assert_eq!(1, admitted.load(Ordering::SeqCst));
+
+        // A new call runs again.
+        let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = serve(b);
+        assert_eq!(300, a.call_once::<_, u64>(Key::new(), &3u64).unwrap());
+        t.join().unwrap().unwrap();
+        assert_eq!(2, admitted.load(Ordering::SeqCst));
+        assert_ne!(Key::new(), Key::new());
+
+        // Another client's call with the same key is its own.
+        let (mut a, b) = Channel::memory_pair("mallory".into());
+        let t = serve(b);
+        assert_eq!(300, a.call_once::<_, u64>(key, &3u64).unwrap());
+        t.join().unwrap().unwrap();
+        assert_eq!(3, admitted.load(Ordering::SeqCst));
+
+        // The same key can't be used for a different call.
+        let (mut a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = serve(b);
+        let error = a.call_once::<_, u64>(key, &4u64).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!(ErrorKind::InvalidInput, t.join().unwrap().unwrap_err().kind());
+        assert_eq!(3, admitted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn call_once_with_deadline() {
+        use std::sync::atomic::AtomicUsize;
+
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let admitted = Arc::new(AtomicUsize::new(0));
+        let count = admitted.clone();
+        let t = thread::spawn(move || {
+            let results = Results::default();
+            let slow = |id: &u64, _: &Deadline| {
+                count.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                id * 100
+            };
+            let error = b.accept_call_once_with_deadline(&results, &slow).unwrap_err();
+            assert_eq!(ErrorKind::Interrupted, error.kind());
+            assert_eq!(3, b.accept_call_once_with_deadline(&results, &slow).unwrap());
+        });
+
+        // The first try gives up, but the result's kept for the retry.
+        let key = Key::new();
+        let deadline = Deadline::after(Duration::from_millis(10));
+        let error = a.call_once_with_deadline::<_, u64>(key, &3u64, &deadline).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        let deadline = Deadline::after(Duration::from_secs(10));
+        assert_eq!(300, a.call_once_with_deadline::<_, u64>(key, &3u64, &deadline).unwrap());
+        t.join().unwrap();
+        assert_eq!(1, admitted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn results_mismatch() {
+        let results = Results::default();
+        let key = Key::new();
+        let running = results.begin(None, key, vec![1]).unwrap().unwrap_err();
+        // Even while the first call is running.
+        assert_eq!(Some(ErrorKind::InvalidInput), results.begin(None, key, vec![2]).err().map(|e| e.kind()));
+        running.finish(vec![1]);
+        assert_eq!(Some(ErrorKind::InvalidInput), results.begin(None, key, vec![2]).err().map(|e| e.kind()));
+        assert_eq!(Some(vec![1]), results.begin(None, key, vec![1]).unwrap().ok());
+    }
+
+    #[test]
+    fn results_expire() {
+        let results = Results::new(Duration::from_millis(10), 1);
+        let key = Key::new();
+        results.begin(None, key, vec![0]).unwrap().unwrap_err().finish(vec![1]);
+        assert_eq!(Some(vec![1]), results.begin(None, key, vec![0]).unwrap().ok());
+
+        // Over capacity the oldest goes.
+        results.begin(None, Key::new(), vec![0]).unwrap().unwrap_err().finish(vec![2]);
+        drop(results.begin(None, key, vec![0]).unwrap().unwrap_err());
+
+        // And after the ttl.
+        let other = Key::new();
+        results.begin(None, other, vec![0]).unwrap().unwrap_err().finish(vec![3]);
+        thread::sleep(Duration::from_millis(20));
+        assert!(results.begin(None, other, vec![0]).unwrap().is_err());
+    }
+
     // #[test]
     // fn remote_call() {
     //     thread::spawn(move || {
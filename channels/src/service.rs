@@ -0,0 +1,1027 @@
+//! Services with many named methods over one channel.
+//!
+//! A `Service` is a table of typed methods, which `serve` dispatches calls to
+//! until the client closes the channel. A `Client` makes calls by method name,
+//! and the `service!` macro generates a trait for the methods along with a
+//! typed client for them.
+//!
+//! A `Client` waits for each call to return before making the next. A
+//! `Pipeline` tags each call with an id instead, so many calls can be in
+//! flight at once, and with `serve_concurrent` they're answered in whatever
+//! order they finish.
+//!
+//! A `Peer` is both at once: each side of the channel serves its own methods
+//! to the other, so a service can call back into its client while answering
+//! it, for progress reports or asking permission.
+//!
+//! Both services and clients can be wrapped in interceptors, which see every
+//! call on its way through, for logging, metrics, checking who's calling, or
+//! turning away bad arguments, without repeating it in every method.
+//!
+//! ```rust,ignore
+//! let service = Service::new()
+//!     .method("add", |(a, b): (u64, u64)| a + b)
+//!     .try_method("div", |(a, b): (u64, u64)| a.checked_div(b).ok_or("divide by zero"));
+//! service.serve(channel)?;
+//!
+//! let mut client = Client::new(channel);
+//! let sum: u64 = client.call("add", &(1u64, 2u64))?;
+//! ```
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use serde::{Serialize, Deserialize};
+use log::{info, error};
+use crate::{Channel, RemoteError};
+use crate::split::{ReadHalf, WriteHalf};
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Request {
+    Call(String, Vec<u8>),
+    /// A call whose reply is tagged with this id.
+    Tagged(u64, String, Vec<u8>),
+    Close,
+    /// Reply to every call, then `Closing`, then close.
+    Drain,
+}
+
+/// What's sent back for tagged calls.
+#[derive(Serialize, Deserialize, Debug)]
+enum Response {
+    Reply(u64, Reply),
+    /// Every call has been replied to.
+    Closing,
+}
+
+/// The answer to a call, as it's sent back to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// The method's result.
+    Ok(Vec<u8>),
+    /// The method failed, with this error.
+    Err(Vec<u8>),
+    /// There's no method with this name.
+    Unknown(String),
+    /// The arguments or result didn't (de)serialize.
+    Invalid(String),
+    /// An interceptor turned the call away, for this reason.
+    Denied(String),
+    /// The method panicked.
+    Failed(String),
+}
+
+/// A call on its way through interceptors.
+#[derive(Debug, Clone, Copy)]
+pub struct Call<'a> {
+    /// The channel's info, see `Channel::info`.
+    pub info: &'a str,
+    pub method: &'a str,
+    pub args: &'a [u8],
+}
+
+impl Call<'_> {
+    /// The arguments, as the given type.
+    pub fn args<D: for<'de> Deserialize<'de>>(&self) -> Result<D, Error> {
+        bincode::deserialize(self.args).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+/// Wraps each call, passing it on to the rest of the chain with the given
+/// function, or answering it itself.
+pub type Interceptor<R> = Box<dyn Fn(&Call, &dyn Fn(&Call) -> R) -> R + Send + Sync>;
+
+/// Pass `call` through `interceptors`, the first outermost, then to `last`.
+fn intercept<R>(interceptors: &[Interceptor<R>], call: &Call, last: &dyn Fn(&Call) -> R) -> R {
+    match interceptors.split_first() {
+        Some((first, rest)) => first(call, &|call| intercept(rest, call, last)),
+        None => last(call),
+    }
+}
+
+type Method = Box<dyn Fn(&[u8]) -> Reply + Send + Sync>;
+
+/// A table of named methods, see the module documentation.
+#[derive(Default)]
+pub struct Service {
+    methods: HashMap<String, Method>,
+    interceptors: Vec<Interceptor<Reply>>,
+}
+
+impl Service {
+    pub fn new() -> Service {
+        Service::default()
+    }
+
+    /// Add a method which always succeeds.
+    pub fn method<D, C, F>(self, name: &str, f: F) -> Service
+        where D: for<'de> Deserialize<'de>,
+              C: Serialize,
+              F: Fn(D) -> C + Send + Sync + 'static,
+    {
+        self.try_method(name, move |d| Ok::<C, ()>(f(d)))
+    }
+
+    /// Add a method which can fail, its errors are returned to the client as
+    /// a `RemoteError<E>`.
+    pub fn try_method<D, C, E, F>(mut self, name: &str, f: F) -> Service
+        where D: for<'de> Deserialize<'de>,
+              C: Serialize,
+              E: Serialize,
+              F: Fn(D) -> Result<C, E> + Send + Sync + 'static,
+    {
+        let method = move |args: &[u8]| {
+            let domain = match bincode::deserialize(args) {
+                Ok(domain) => domain,
+                Err(e) => return Reply::Invalid(format!("arguments: {}", e)),
+            };
+            let reply = match f(domain) {
+                Ok(codomain) => bincode::serialize(&codomain).map(Reply::Ok),
+                Err(e) => bincode::serialize(&e).map(Reply::Err),
+            };
+            reply.unwrap_or_else(|e| Reply::Invalid(format!("result: {}", e)))
+        };
+        self.methods.insert(name.into(), Box::new(method));
+        self
+    }
+
+    /// Wrap every call in `interceptor`, inside any added before it.
+    pub fn intercept<F>(mut self, interceptor: F) -> Service
+        where F: Fn(&Call, &dyn Fn(&Call) -> Reply) -> Reply + Send + Sync + 'static,
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Answer a call, a method which panics fails only its own call.
+    fn dispatch(&self, info: &str, name: String, args: &[u8]) -> Reply {
+        info!("dispatching {:?}", name);
+        let call = Call { info, method: &name, args };
+        let reply = panic::catch_unwind(AssertUnwindSafe(|| {
+            intercept(&self.interceptors, &call, &|call| match self.methods.get(call.method) {
+                Some(method) => method(call.args),
+                None => Reply::Unknown(call.method.into()),
+            })
+        }));
+        reply.unwrap_or_else(|_| {
+            error!("method {:?} panicked", name);
+            Reply::Failed(name.clone())
+        })
+    }
+
+    /// Answer calls on `channel` one at a time, until the client closes it.
+    /// Hanging up without closing is an error.
+    pub fn serve(&self, mut channel: Channel) -> Result<(), Error> {
+        loop {
+            match channel.recv()? {
+                Request::Call(name, args) => {
+                    let reply = self.dispatch(channel.info(), name, &args);
+                    channel.send(&reply)?;
+                }
+                Request::Tagged(id, name, args) => {
+                    let reply = self.dispatch(channel.info(), name, &args);
+                    channel.send(&Response::Reply(id, reply))?;
+                }
+                Request::Close => return channel.close(),
+                Request::Drain => {
+                    channel.send(&Response::Closing)?;
+                    return channel.close();
+                }
+            }
+        }
+    }
+
+    /// Like `serve`, but answering up to `workers` tagged calls at once, each
+    /// as soon as it's done. This fails if the channel can't be split.
+    pub fn serve_concurrent(&self, channel: Channel, workers: usize) -> Result<(), Error> {
+        assert!(workers > 0, "service needs at least one worker");
+        let info = channel.info().to_owned();
+        let (mut read, write) = channel.split()?;
+        let write = Mutex::new(write);
+        let (tx, rx) = mpsc::sync_channel::<(u64, String, Vec<u8>)>(0);
+        let rx = Mutex::new(rx);
+        let ending = thread::scope(|s| {
+            for _ in 0..workers {
+                s.spawn(|| loop {
+                    let job = rx.lock().unwrap().recv();
+                    let (id, name, args) = match job {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    let reply = self.dispatch(&info, name, &args);
+                    if let Err(e) = write.lock().unwrap().send(&Response::Reply(id, reply)) {
+                        error!("error replying to call {}: {}", id, e);
+                    }
+                });
+            }
+            let ending = loop {
+                match read.recv() {
+                    Ok(Request::Call(name, args)) => {
+                        let reply = self.dispatch(&info, name, &args);
+                        if let Err(e) = write.lock().unwrap().send(&reply) {
+                            break Err(e);
+                        }
+                    }
+                    Ok(Request::Tagged(id, name, args)) => {
+                        if tx.send((id, name, args)).is_err() {
+                            break Err(Error::other("service workers stopped"));
+                        }
+                    }
+                    Ok(request) => break Ok(request),
+                    Err(e) => break Err(e),
+                }
+            };
+            drop(tx);
+            ending
+        });
+        let mut write = write.into_inner().unwrap();
+        if let Request::Drain = ending? {
+            write.send(&Response::Closing)?;
+        }
+        Channel::reunite(read, write).map_err(|e| Error::other(e.to_string()))?.close()
+    }
+}
+
+/// Turn a reply into the result of a call.
+fn decode<C, E>(reply: Reply) -> Result<C, Error>
+    where C: for<'de> Deserialize<'de>,
+          E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+{
+    let decode = |e| Error::new(ErrorKind::InvalidData, e);
+    match reply {
+        Reply::Ok(bytes) => bincode::deserialize(&bytes).map_err(decode),
+        Reply::Err(bytes) => {
+            let e: E = bincode::deserialize(&bytes).map_err(decode)?;
+            Err(Error::other(RemoteError(e)))
+        }
+        Reply::Unknown(name) => {
+            Err(Error::new(ErrorKind::Unsupported, format!("no method named {:?}", name)))
+        }
+        Reply::Invalid(message) => Err(Error::new(ErrorKind::InvalidInput, message)),
+        Reply::Denied(reason) => Err(Error::new(ErrorKind::PermissionDenied, reason)),
+        Reply::Failed(name) => Err(Error::other(format!("method {:?} panicked", name))),
+    }
+}
+
+/// Calls the methods of a service by name.
+pub struct Client {
+    channel: Channel,
+    interceptors: Vec<Interceptor<Result<Reply, Error>>>,
+}
+
+impl Client {
+    pub fn new(channel: Channel) -> Client {
+        Client { channel, interceptors: vec![] }
+    }
+
+    /// Wrap every call in `interceptor`, inside any added before it. The
+    /// innermost step sends the call and receives its reply.
+    pub fn intercept<F>(mut self, interceptor: F) -> Client
+        where F: Fn(&Call, &dyn Fn(&Call) -> Result<Reply, Error>) -> Result<Reply, Error> + Send + Sync + 'static,
+    {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Call a method which always succeeds.
+    pub fn call<D, C>(&mut self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+    {
+        self.try_call::<D, C, String>(method, args)
+    }
+
+    /// Call a method which can fail, its error comes back as a
+    /// `RemoteError<E>`, see `RemoteError::downcast`.
+    pub fn try_call<D, C, E>(&mut self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        let args = bincode::serialize(args).map_err(Error::other)?;
+        let info = self.channel.info().to_owned();
+        let call = Call { info: &info, method, args: &args };
+        let channel = RefCell::new(&mut self.channel);
+        let reply = intercept(&self.interceptors, &call, &|call| {
+            let mut channel = channel.borrow_mut();
+            channel.send(&Request::Call(call.method.into(), call.args.to_vec()))?;
+            channel.recv()
+        })?;
+        decode::<C, E>(reply)
+    }
+
+    /// Tell the service we're done, and close the channel.
+    pub fn close(mut self) -> Result<(), Error> {
+        self.channel.send(&Request::Close)?;
+        self.channel.close()
+    }
+}
+
+/// Replies for a pipeline's calls, by id, and whether more can arrive.
+///
+/// Only calls someone's waiting for have an entry, which is `None` until
+/// their reply arrives.
+#[derive(Default)]
+struct Replies {
+    replies: Mutex<(HashMap<u64, Option<Reply>>, bool)>,
+    changed: Condvar,
+}
+
+impl Replies {
+    /// Wait for a reply to call `id`, this must be before it's sent.
+    fn expect(&self, id: u64) {
+        self.replies.lock().unwrap().0.insert(id, None);
+    }
+
+    /// A reply has arrived, this is false if nobody's waiting for it.
+    fn arrived(&self, id: u64, reply: Reply) -> bool {
+        let mut replies = self.replies.lock().unwrap();
+        match replies.0.get_mut(&id) {
+            Some(slot @ None) => {
+                *slot = Some(reply);
+                self.changed.notify_all();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// No more replies are coming.
+    fn lost(&self) {
+        self.replies.lock().unwrap().1 = true;
+        self.changed.notify_all();
+    }
+}
+
+/// Makes many calls at once over one channel, see the module documentation.
+pub struct Pipeline {
+    write: Mutex<WriteHalf>,
+    replies: Arc<Replies>,
+    next: AtomicU64,
+    reader: JoinHandle<Option<ReadHalf>>,
+}
+
+impl Pipeline {
+    /// Start making calls over `channel`, this fails if the channel can't be
+    /// split.
+    pub fn new(channel: Channel) -> Result<Pipeline, Error> {
+        let (mut read, write) = channel.split()?;
+        let replies = Arc::new(Replies::default());
+        let r = replies.clone();
+        let reader = thread::spawn(move || {
+            let read = loop {
+                match read.recv() {
+                    Ok(Response::Reply(id, reply)) => {
+                        if !r.arrived(id, reply) {
+                            info!("dropping reply to abandoned call {}", id);
+                        }
+                    }
+                    Ok(Response::Closing) => break Some(read),
+                    Err(e) => {
+                        error!("pipeline lost: {}", e);
+                        break None;
+                    }
+                }
+            };
+            r.lost();
+            read
+        });
+        Ok(Pipeline {
+            write: Mutex::new(write),
+            replies,
+            next: AtomicU64::new(0),
+            reader,
+        })
+    }
+
+    /// Start a call to a method which always succeeds.
+    pub fn call<D, C>(&self, method: &str, args: &D) -> Result<Pending<C>, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+    {
+        self.try_call(method, args)
+    }
+
+    /// Start a call to a method which can fail, see `Client::try_call`.
+    pub fn try_call<D, C, E>(&self, method: &str, args: &D) -> Result<Pending<C, E>, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        let id = self.next.fetch_add(1, Ordering::SeqCst);
+        let args = bincode::serialize(args).map_err(Error::other)?;
+        self.replies.expect(id);
+        let pending = Pending { id, replies: self.replies.clone(), types: PhantomData };
+        self.write.lock().unwrap().send(&Request::Tagged(id, method.into(), args))?;
+        Ok(pending)
+    }
+
+    /// Wait for every call to be replied to, then close the channel.
+    pub fn close(self) -> Result<(), Error> {
+        let mut write = self.write.into_inner().unwrap();
+        write.send(&Request::Drain)?;
+        let read = self.reader.join().unwrap()
+            .ok_or_else(|| Error::new(ErrorKind::ConnectionAborted, "pipeline lost"))?;
+        Channel::reunite(read, write).map_err(|e| Error::other(e.to_string()))?.close()
+    }
+}
+
+/// A call in flight, see `Pipeline`. Dropping it without waiting discards
+/// the call's reply.
+pub struct Pending<C, E = String> {
+    id: u64,
+    replies: Arc<Replies>,
+    types: PhantomData<fn() -> (C, E)>,
+}
+
+impl<C, E> Pending<C, E>
+    where C: for<'de> Deserialize<'de>,
+          E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+{
+    /// Wait for the call's result.
+    pub fn wait(self) -> Result<C, Error> {
+        let mut replies = self.replies.replies.lock().unwrap();
+        loop {
+            if let Some(reply) = replies.0.get_mut(&self.id).and_then(Option::take) {
+                return decode::<C, E>(reply);
+            }
+            if replies.1 {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "pipeline lost"));
+            }
+            replies = self.replies.changed.wait(replies).unwrap();
+        }
+    }
+}
+
+impl<C, E> Drop for Pending<C, E> {
+    fn drop(&mut self) {
+        if let Ok(mut replies) = self.replies.replies.lock() {
+            replies.0.remove(&self.id);
+        }
+    }
+}
+
+/// What peers send each other.
+#[derive(Serialize, Deserialize, Debug)]
+enum Frame {
+    Call(u64, String, Vec<u8>),
+    Reply(u64, Reply),
+    /// No more calls are coming from this side.
+    Close,
+}
+
+/// The sending side of a peer.
+struct Outgoing {
+    write: Mutex<WriteHalf>,
+    replies: Arc<Replies>,
+    next: AtomicU64,
+    /// Our calls still waiting for a reply, and whether the other side has
+    /// closed.
+    calls: Mutex<(usize, bool)>,
+}
+
+impl Outgoing {
+    fn send(&self, frame: &Frame) -> Result<(), Error> {
+        self.write.lock().unwrap().send(frame)
+    }
+}
+
+/// Calls the other side of a `Peer`, from any thread.
+#[derive(Clone)]
+pub struct Caller(Arc<Outgoing>);
+
+impl Caller {
+    /// Call a method which always succeeds.
+    pub fn call<D, C>(&self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+    {
+        self.try_call::<D, C, String>(method, args)
+    }
+
+    /// Call a method which can fail, see `Client::try_call`.
+    pub fn try_call<D, C, E>(&self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        let id = self.0.next.fetch_add(1, Ordering::SeqCst);
+        let args = bincode::serialize(args).map_err(Error::other)?;
+        {
+            let mut calls = self.0.calls.lock().unwrap();
+            if calls.1 {
+                return Err(Error::new(ErrorKind::ConnectionAborted, "peer closed"));
+            }
+            calls.0 += 1;
+        }
+        self.0.replies.expect(id);
+        let pending = Pending::<C, E> { id, replies: self.0.replies.clone(), types: PhantomData };
+        if let Err(e) = self.0.send(&Frame::Call(id, method.into(), args)) {
+            self.0.calls.lock().unwrap().0 -= 1;
+            return Err(e);
+        }
+        pending.wait()
+    }
+}
+
+/// One side of a channel where both sides serve methods to each other, see
+/// the module documentation.
+///
+/// Both sides need to `close` their peer, which waits for the other side to
+/// stop calling too. Once the other side has closed, calls to it fail.
+pub struct Peer {
+    caller: Caller,
+    reader: JoinHandle<Result<ReadHalf, Error>>,
+}
+
+impl Peer {
+    /// Start serving the methods `service` returns, which is given a
+    /// `Caller` for calling back to the other side. Up to `workers` calls
+    /// are answered at once, and the rest wait their turn, while replies to
+    /// our own calls keep being read. This fails if the channel can't be
+    /// split.
+    pub fn new<F>(channel: Channel, workers: usize, service: F) -> Result<Peer, Error>
+        where F: FnOnce(Caller) -> Service,
+    {
+        assert!(workers > 0, "peer needs at least one worker");
+        let info = channel.info().to_owned();
+        let (mut read, write) = channel.split()?;
+        let caller = Caller(Arc::new(Outgoing {
+            write: Mutex::new(write),
+            replies: Arc::new(Replies::default()),
+            next: AtomicU64::new(0),
+            calls: Mutex::new((0, false)),
+        }));
+        let service = service(caller.clone());
+        let outgoing = caller.0.clone();
+        let reader = thread::spawn(move || {
+            // Calls are answered by the workers, so they can call back. The
+            // queue isn't bounded, since the reader mustn't block on it while
+            // a worker waits for a reply.
+            let (tx, rx) = mpsc::channel::<(u64, String, Vec<u8>)>();
+            let rx = Mutex::new(rx);
+            let result = thread::scope(|s| {
+                for _ in 0..workers {
+                    s.spawn(|| loop {
+                        let job = rx.lock().unwrap().recv();
+                        let (id, name, args) = match job {
+                            Ok(job) => job,
+                            Err(_) => return,
+                        };
+                        let reply = service.dispatch(&info, name, &args);
+                        if let Err(e) = outgoing.send(&Frame::Reply(id, reply)) {
+                            error!("error replying to call {}: {}", id, e);
+                        }
+                    });
+                }
+                let result = loop {
+                    let frame = match read.recv() {
+                        Ok(frame) => frame,
+                        Err(e) => break Err(e),
+                    };
+                    match frame {
+                        Frame::Call(id, name, args) => {
+                            // The workers only stop once this does.
+                            tx.send((id, name, args)).unwrap();
+                        }
+                        Frame::Reply(id, reply) => {
+                            if !outgoing.replies.arrived(id, reply) {
+                                let error = format!("reply to unknown call {}", id);
+                                break Err(Error::new(ErrorKind::InvalidData, error));
+                            }
+                            let mut calls = outgoing.calls.lock().unwrap();
+                            calls.0 -= 1;
+                            if *calls == (0, true) {
+                                break Ok(());
+                            }
+                        }
+                        Frame::Close => {
+                            // Wait for replies to our calls, but make no more.
+                            let mut calls = outgoing.calls.lock().unwrap();
+                            calls.1 = true;
+                            if calls.0 == 0 {
+                                break Ok(());
+                            }
+                        }
+                    }
+                };
+                // Calls still waiting on a reply won't get one now.
+                outgoing.replies.lost();
+                drop(tx);
+                result
+            });
+            result.map(|()| read)
+        });
+        Ok(Peer { caller, reader })
+    }
+
+    /// A handle for calling the other side from other threads.
+    pub fn caller(&self) -> Caller {
+        self.caller.clone()
+    }
+
+    /// Call a method which always succeeds.
+    pub fn call<D, C>(&self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+    {
+        self.caller.call(method, args)
+    }
+
+    /// Call a method which can fail, see `Client::try_call`.
+    pub fn try_call<D, C, E>(&self, method: &str, args: &D) -> Result<C, Error>
+        where D: Serialize,
+              C: for<'de> Deserialize<'de>,
+              E: for<'de> Deserialize<'de> + Debug + Display + Send + Sync + 'static,
+    {
+        self.caller.try_call::<D, C, E>(method, args)
+    }
+
+    /// Stop calling the other side, keep answering it until it closes too,
+    /// and then close the channel.
+    pub fn close(self) -> Result<(), Error> {
+        self.caller.0.send(&Frame::Close)?;
+        let read = self.reader.join().unwrap()?;
+        let outgoing = Arc::try_unwrap(self.caller.0)
+            .map_err(|_| Error::other("peer closed while its callers are still in use"))?;
+        let write = outgoing.write.into_inner().unwrap();
+        Channel::reunite(read, write).map_err(|e| Error::other(e.to_string()))?.close()
+    }
+}
+
+/// Declare a service's methods as a trait, and generate a typed client for
+/// them. Every method takes `&self` and returns a `Result`, whose error is
+/// sent back to the client.
+///
+/// ```rust,ignore
+/// channels::service! {
+///     pub trait Calculator, client CalculatorClient {
+///         fn div(&self, a: u64, b: u64) -> Result<u64, String>;
+///     }
+/// }
+///
+/// // Serving an implementation.
+/// MyCalculator.into_service().serve(channel)?;
+///
+/// // Calling it.
+/// let mut calculator = CalculatorClient::new(channel);
+/// assert_eq!(2, calculator.div(4, 2)?);
+/// ```
+#[macro_export]
+macro_rules! service {
+    (
+        $(#[$attr:meta])*
+        $vis:vis trait $name:ident, client $client:ident {
+            $(
+                $(#[$method_attr:meta])*
+                fn $method:ident(&self $(, $arg:ident: $ty:ty)*) -> Result<$ok:ty, $err:ty>;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        $vis trait $name: Send + Sync + 'static {
+            $(
+                $(#[$method_attr])*
+                fn $method(&self $(, $arg: $ty)*) -> Result<$ok, $err>;
+            )*
+
+            /// A service answering calls with this implementation.
+            fn into_service(self) -> $crate::service::Service where Self: Sized {
+                let this = ::std::sync::Arc::new(self);
+                $crate::service::Service::new()
+                $(
+                    .try_method(stringify!($method), {
+                        let this = this.clone();
+                        move |($($arg,)*): ($($ty,)*)| this.$method($($arg),*)
+                    })
+                )*
+            }
+        }
+
+        /// A typed client for the service.
+        $vis struct $client($crate::service::Client);
+
+        impl $client {
+            $vis fn new(channel: $crate::Channel) -> $client {
+                $client($crate::service::Client::new(channel))
+            }
+
+            $(
+                $(#[$method_attr])*
+                $vis fn $method(&mut self $(, $arg: $ty)*) -> Result<$ok, ::std::io::Error> {
+                    self.0.try_call::<_, _, $err>(stringify!($method), &($($arg,)*))
+                }
+            )*
+
+            /// Tell the service we're done, and close the channel.
+            $vis fn close(self) -> Result<(), ::std::io::Error> {
+                self.0.close()
+            }
+        }
+
+        /// Make typed calls through a client, which may have interceptors.
+        impl From<$crate::service::Client> for $client {
+            fn from(client: $crate::service::Client) -> $client {
+                $client(client)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    fn calculator() -> Service {
+        Service::new()
+            .method("add", |(a, b): (u64, u64)| a + b)
+            .method("show", |b: bool| format!("{}", b))
+            .try_method("div", |(a, b): (u64, u64)| a.checked_div(b).ok_or("divide by zero"))
+    }
+
+    #[test]
+    fn dispatch() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || calculator().serve(b));
+        let mut client = Client::new(a);
+        assert_eq!(3u64, client.call::<_, u64>("add", &(1u64, 2u64)).unwrap());
+        assert_eq!("false", client.call::<_, String>("show", &false).unwrap());
+        assert_eq!(2u64, client.try_call::<_, u64, String>("div", &(4u64, 2u64)).unwrap());
+
+        let error = client.try_call::<_, u64, String>("div", &(4u64, 0u64)).unwrap_err();
+        let reason = RemoteError::<String>::downcast(&error);
+        assert_eq!(Some(&"divide by zero".to_string()), reason);
+
+        let error = client.call::<_, u64>("mul", &(4u64, 2u64)).unwrap_err();
+        assert_eq!(ErrorKind::Unsupported, error.kind());
+        let error = client.call::<_, u64>("add", &"four").unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+
+        client.close().unwrap();
+        assert!(t.join().unwrap().is_ok());
+    }
+
+    fn slow() -> Service {
+        Service::new()
+            .method("sleep", |ms: u64| {
+                thread::sleep(std::time::Duration::from_millis(ms));
+                ms
+            })
+    }
+
+    #[test]
+    fn pipelined() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || slow().serve_concurrent(b, 4));
+        let pipeline = Pipeline::new(a).unwrap();
+        let slow = pipeline.call::<_, u64>("sleep", &200u64).unwrap();
+        let fast = pipeline.call::<_, u64>("sleep", &0u64).unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(0, fast.wait().unwrap());
+        assert!(start.elapsed() < std::time::Duration::from_millis(200));
+        assert_eq!(200, slow.wait().unwrap());
+
+        // Hundreds in flight at once.
+        let pending = (0..300).map(|_| pipeline.call::<_, u64>("sleep", &1u64).unwrap())
+            .collect::<Vec<_>>();
+        let missing = pipeline.call::<_, u64>("nap", &1u64).unwrap();
+        for p in pending {
+            assert_eq!(1, p.wait().unwrap());
+        }
+        assert_eq!(ErrorKind::Unsupported, missing.wait().unwrap_err().kind());
+        pipeline.close().unwrap();
+        t.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn pipelined_panics() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let service = slow().method("panic", |(): ()| -> u64 { panic!("oops") });
+        let t = thread::spawn(move || service.serve_concurrent(b, 1));
+        let pipeline = Pipeline::new(a).unwrap();
+        let error = pipeline.call::<_, u64>("panic", &()).unwrap().wait().unwrap_err();
+        assert!(error.to_string().contains("panicked"));
+        // The worker's still answering calls.
+        assert_eq!(1, pipeline.call::<_, u64>("sleep", &1u64).unwrap().wait().unwrap());
+
+        // Calls nobody waits for don't keep their replies.
+        drop(pipeline.call::<_, u64>("sleep", &0u64).unwrap());
+        assert_eq!(2, pipeline.call::<_, u64>("sleep", &2u64).unwrap().wait().unwrap());
+        assert!(pipeline.replies.replies.lock().unwrap().0.is_empty());
+        pipeline.close().unwrap();
+        t.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn pipelined_in_order() {
+        // A plain serve loop answers tagged calls too, one at a time.
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || calculator().serve(b));
+        let pipeline = Pipeline::new(a).unwrap();
+        let sum = pipeline.call::<_, u64>("add", &(1u64, 2u64)).unwrap();
+        let div = pipeline.try_call::<_, u64, String>("div", &(1u64, 0u64)).unwrap();
+        assert!(div.wait().is_err());
+        assert_eq!(3, sum.wait().unwrap());
+        pipeline.close().unwrap();
+        t.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn hang_up() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || calculator().serve(b));
+        drop(a);
+        assert!(t.join().unwrap().is_err());
+    }
+
+    crate::service! {
+        /// Things to do with a word.
+        trait Words, client WordsClient {
+            fn repeat(&self, word: String, n: usize) -> Result<String, String>;
+            fn shout(&self, word: String) -> Result<String, String>;
+            fn count(&self) -> Result<u64, String>;
+        }
+    }
+
+    struct Echo;
+
+    impl Words for Echo {
+        fn repeat(&self, word: String, n: usize) -> Result<String, String> {
+            if n == 0 {
+                Err("nothing to repeat".into())
+            } else {
+                Ok(word.repeat(n))
+            }
+        }
+
+        fn shout(&self, word: String) -> Result<String, String> {
+            Ok(word.to_uppercase())
+        }
+
+        fn count(&self) -> Result<u64, String> {
+            Ok(3)
+        }
+    }
+
+    #[test]
+    fn generated_client() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let t = thread::spawn(move || Echo.into_service().serve(b));
+        let mut words = WordsClient::new(a);
+        assert_eq!("hihi", words.repeat("hi".into(), 2).unwrap());
+        assert!(words.repeat("hi".into(), 0).is_err());
+        assert_eq!("HI", words.shout("hi".into()).unwrap());
+        assert_eq!(3, words.count().unwrap());
+        words.close().unwrap();
+        t.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn interceptors() {
+        use std::sync::atomic::AtomicUsize;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = calls.clone();
+        let service = calculator()
+            .intercept(move |call, next| {
+                counted.fetch_add(1, Ordering::SeqCst);
+                next(call)
+            })
+            .intercept(|call, next| match call.info {
+                "nixpulvis" => next(call),
+                who => Reply::Denied(format!("{} can't call {}", who, call.method)),
+            })
+            .intercept(|call, next| match (call.method, call.args::<(u64, u64)>()) {
+                ("add", Ok((a, b))) if a.checked_add(b).is_none() => Reply::Invalid("overflow".into()),
+                _ => next(call),
+            });
+
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (c, d) = Channel::memory_pair("mallory".into());
+        let service = Arc::new(service);
+        let s = service.clone();
+        let t = thread::spawn(move || s.serve(b));
+        let u = thread::spawn(move || service.serve(d));
+
+        // The client's interceptors run in order, and can answer calls
+        // themselves.
+        let order = Arc::new(Mutex::new(vec![]));
+        let (first, second) = (order.clone(), order.clone());
+        let mut client = Client::new(a)
+            .intercept(move |call, next| {
+                first.lock().unwrap().push(format!("before {}", call.method));
+                let reply = next(call);
+                first.lock().unwrap().push(format!("after {}", call.method));
+                reply
+            })
+            .intercept(move |call, next| {
+                second.lock().unwrap().push("inner".into());
+                match call.method {
+                    "show" => Ok(Reply::Ok(bincode::serialize("cached").unwrap())),
+                    _ => next(call),
+                }
+            });
+        assert_eq!(3u64, client.call::<_, u64>("add", &(1u64, 2u64)).unwrap());
+        let error = client.call::<_, u64>("add", &(u64::MAX, 1u64)).unwrap_err();
+        assert_eq!(ErrorKind::InvalidInput, error.kind());
+        assert_eq!("cached", client.call::<_, String>("show", &true).unwrap());
+        assert_eq!(vec!["before add", "inner", "after add"], order.lock().unwrap()[..3].to_vec());
+        client.close().unwrap();
+
+        let mut stranger = Client::new(c);
+        let error = stranger.call::<_, u64>("add", &(1u64, 2u64)).unwrap_err();
+        assert_eq!(ErrorKind::PermissionDenied, error.kind());
+        assert_eq!("mallory can't call add", error.to_string());
+        stranger.close().unwrap();
+
+        t.join().unwrap().unwrap();
+        u.join().unwrap().unwrap();
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn peers() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (done, client_done) = mpsc::channel();
+
+        // The server reports progress back to the client, and asks it first.
+        let server = thread::spawn(move || {
+            let server = Peer::new(b, 2, |client| Service::new()
+                .try_method("work", move |steps: u64| {
+                    if !client.call::<_, bool>("allow", &steps).map_err(|e| e.to_string())? {
+                        return Err("not allowed".to_string());
+                    }
+                    for step in 0..steps {
+                        client.call::<_, ()>("progress", &step).map_err(|e| e.to_string())?;
+                    }
+                    Ok(steps)
+                })
+            ).unwrap();
+            client_done.recv().unwrap();
+            assert_eq!("hi", server.call::<_, String>("name", &()).unwrap());
+            server.close()
+        });
+
+        let progress = Arc::new(Mutex::new(vec![]));
+        let p = progress.clone();
+        let client = Peer::new(a, 2, move |_| Service::new()
+            .method("allow", |steps: u64| steps < 10)
+            .method("progress", move |step: u64| p.lock().unwrap().push(step))
+            .method("name", |()| "hi".to_string())
+        ).unwrap();
+        assert_eq!(3, client.try_call::<_, u64, String>("work", &3u64).unwrap());
+        assert_eq!(vec![0, 1, 2], *progress.lock().unwrap());
+        let error = client.try_call::<_, u64, String>("work", &100u64).unwrap_err();
+        assert_eq!(Some(&"not allowed".to_string()), RemoteError::<String>::downcast(&error));
+        done.send(()).unwrap();
+
+        // Once the server's closed, calling it fails.
+        while client.call::<_, u64>("work", &0u64).is_ok() {}
+        client.close().unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn peer_workers() {
+        use std::sync::atomic::AtomicUsize;
+
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (busy, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (b1, m1) = (busy.clone(), most.clone());
+        let server = Peer::new(b, 2, move |_| Service::new()
+            .method("work", move |()| {
+                m1.fetch_max(b1.fetch_add(1, Ordering::SeqCst) + 1, Ordering::SeqCst);
+                thread::sleep(std::time::Duration::from_millis(20));
+                b1.fetch_sub(1, Ordering::SeqCst);
+            })
+        ).unwrap();
+        let client = Peer::new(a, 1, |_| Service::new()).unwrap();
+        let calls: Vec<_> = (0..6).map(|_| {
+            let caller = client.caller();
+            thread::spawn(move || caller.call::<_, ()>("work", &()))
+        }).collect();
+        for call in calls {
+            call.join().unwrap().unwrap();
+        }
+        assert_eq!(2, most.load(Ordering::SeqCst));
+        let t = thread::spawn(move || server.close());
+        client.close().unwrap();
+        t.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn peer_unknown_reply() {
+        let (a, mut b) = Channel::memory_pair("nixpulvis".into());
+        let peer = Peer::new(a, 1, |_| Service::new()).unwrap();
+        b.send(&Frame::Reply(7, Reply::Ok(vec![]))).unwrap();
+        let error = peer.close().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+    }
+}
@@ -0,0 +1,613 @@
+//! Deterministic network simulation.
+//!
+//! A `Network` runs each node's code on its own thread, but only lets one
+//! node run at a time, handing control between them with a seeded scheduler.
+//! Nodes talk over in-memory channels whose messages take a (virtual) while
+//! to arrive, and links can be partitioned and healed at set times. Time only
+//! moves forward when every node is waiting, so a whole run takes as long as
+//! the code does, and the same seed always gives the same interleaving.
+//!
+//! When every node is waiting and nothing is in flight, the network is stuck,
+//! so blocked reads fail with `ErrorKind::TimedOut` rather than hang.
+//!
+//! ```rust,ignore
+//! let mut net = Network::new(seed);
+//! let (alice, bob) = (net.node("alice"), net.node("bob"));
+//! let (mut a, mut b) = net.link(alice, bob, ms(5)..ms(20));
+//! net.partition(alice, bob, ms(0), Some(ms(100)));
+//! net.spawn(alice, move || a.send(&1u8).unwrap());
+//! net.spawn(bob, move || assert_eq!(1u8, b.recv().unwrap()));
+//! let elapsed = net.run();
+//! ```
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use log::info;
+use crate::Channel;
+use crate::transport::Transport;
+
+/// A node in a simulated network.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Node(usize);
+
+/// A simulated network of nodes, see the module documentation.
+pub struct Network {
+    shared: Arc<Shared>,
+    tasks: Vec<(Node, Box<dyn FnOnce() + Send>)>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+struct State {
+    rng: Rng,
+    now: Duration,
+    seq: u64,
+    running: Option<Node>,
+    nodes: Vec<NodeState>,
+    endpoints: Vec<Endpoint>,
+    events: BTreeMap<(Duration, u64), Event>,
+    partitions: HashSet<(Node, Node)>,
+    held: Vec<(usize, Payload)>,
+}
+
+struct NodeState {
+    name: String,
+    status: Status,
+    stuck: bool,
+    panicked: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Runnable,
+    Blocked,
+    Done,
+}
+
+/// One end of a link, owned by `node`.
+struct Endpoint {
+    node: Node,
+    peer: usize,
+    latency: Range<Duration>,
+    buffer: VecDeque<u8>,
+    eof: bool,
+    dropped: bool,
+    /// When the last message to this endpoint arrives, to keep them in order.
+    last: Duration,
+}
+
+enum Event {
+    Deliver(usize, Payload),
+    Partition(Node, Node),
+    Heal(Node, Node),
+    Wake(Node),
+}
+
+enum Payload {
+    Data(Vec<u8>),
+    Eof,
+}
+
+/// A small, seedable random number generator (SplitMix64).
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 { 0 } else { self.next() % n }
+    }
+}
+
+fn pair(a: Node, b: Node) -> (Node, Node) {
+    if a.0 <= b.0 { (a, b) } else { (b, a) }
+}
+
+impl State {
+    fn schedule(&mut self, at: Duration, event: Event) {
+        self.seq += 1;
+        self.events.insert((at, self.seq), event);
+    }
+
+    /// Send a payload to endpoint `to`, arriving after the link's latency.
+    fn transmit(&mut self, to: usize, payload: Payload) {
+        let latency = self.endpoints[to].latency.clone();
+        let spread = (latency.end.saturating_sub(latency.start)).as_nanos() as u64;
+        let delay = latency.start + Duration::from_nanos(self.rng.below(spread));
+        let at = (self.now + delay).max(self.endpoints[to].last);
+        self.endpoints[to].last = at;
+        self.schedule(at, Event::Deliver(to, payload));
+    }
+
+    fn wake(&mut self, node: Node) {
+        if self.nodes[node.0].status == Status::Blocked {
+            self.nodes[node.0].status = Status::Runnable;
+        }
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::Deliver(to, payload) => {
+                let from = self.endpoints[self.endpoints[to].peer].node;
+                let node = self.endpoints[to].node;
+                if self.partitions.contains(&pair(from, node)) {
+                    self.held.push((to, payload));
+                    return;
+                }
+                match payload {
+                    Payload::Data(bytes) => self.endpoints[to].buffer.extend(bytes),
+                    Payload::Eof => self.endpoints[to].eof = true,
+                }
+                self.wake(node);
+            }
+            Event::Partition(a, b) => {
+                info!("partitioning {} and {}", self.nodes[a.0].name, self.nodes[b.0].name);
+                self.partitions.insert(pair(a, b));
+            }
+            Event::Heal(a, b) => {
+                info!("healing {} and {}", self.nodes[a.0].name, self.nodes[b.0].name);
+                self.partitions.remove(&pair(a, b));
+                let held = std::mem::take(&mut self.held);
+                for (to, payload) in held {
+                    let from = self.endpoints[self.endpoints[to].peer].node;
+                    if pair(from, self.endpoints[to].node) == pair(a, b) {
+                        self.transmit(to, payload);
+                    } else {
+                        self.held.push((to, payload));
+                    }
+                }
+            }
+            Event::Wake(node) => self.wake(node),
+        }
+    }
+
+    /// Pick the next node to run, moving time forward until one can.
+    fn pick(&mut self) {
+        loop {
+            let runnable = self.nodes.iter().enumerate()
+                .filter(|(_, n)| n.status == Status::Runnable)
+                .map(|(i, _)| Node(i))
+                .collect::<Vec<_>>();
+            if !runnable.is_empty() {
+                let i = self.rng.below(runnable.len() as u64) as usize;
+                self.running = Some(runnable[i]);
+                return;
+            }
+            if self.nodes.iter().all(|n| n.status == Status::Done) {
+                self.running = None;
+                return;
+            }
+            let next = self.events.keys().next().cloned();
+            match next {
+                Some(key) => {
+                    let event = self.events.remove(&key).unwrap();
+                    self.now = self.now.max(key.0);
+                    self.apply(event);
+                }
+                None => {
+                    info!("network stuck at {:?}", self.now);
+                    for node in &mut self.nodes {
+                        if node.status == Status::Blocked {
+                            node.status = Status::Runnable;
+                            node.stuck = true;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn wait_turn<'a>(&self, mut state: MutexGuard<'a, State>, me: Node) -> MutexGuard<'a, State> {
+        while state.running != Some(me) {
+            state = self.turn.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state
+    }
+
+    /// Give up control, with our status already set, until we're picked again.
+    fn yield_now<'a>(&self, mut state: MutexGuard<'a, State>, me: Node) -> MutexGuard<'a, State> {
+        state.pick();
+        self.turn.notify_all();
+        self.wait_turn(state, me)
+    }
+}
+
+impl Network {
+    /// Create an empty network, with all its choices made by `seed`.
+    pub fn new(seed: u64) -> Network {
+        let state = State {
+            rng: Rng(seed),
+            now: Duration::from_secs(0),
+            seq: 0,
+            running: None,
+            nodes: vec![],
+            endpoints: vec![],
+            events: BTreeMap::new(),
+            partitions: HashSet::new(),
+            held: vec![],
+        };
+        Network {
+            shared: Arc::new(Shared { state: Mutex::new(state), turn: Condvar::new() }),
+            tasks: vec![],
+        }
+    }
+
+    /// Add a node to the network.
+    pub fn node(&mut self, name: &str) -> Node {
+        let mut state = self.shared.lock();
+        state.nodes.push(NodeState {
+            name: name.into(),
+            status: Status::Done,
+            stuck: false,
+            panicked: false,
+        });
+        Node(state.nodes.len() - 1)
+    }
+
+    /// Connect two nodes, returning a channel for each. Messages take
+    /// somewhere in `latency` to arrive, and each channel's info is the name
+    /// of the node at the other end.
+    ///
+    /// A channel must only be used by the node it belongs to.
+    pub fn link(&mut self, a: Node, b: Node, latency: Range<Duration>) -> (Channel, Channel) {
+        let mut state = self.shared.lock();
+        let (i, j) = (state.endpoints.len(), state.endpoints.len() + 1);
+        for (node, peer) in [(a, j), (b, i)] {
+            state.endpoints.push(Endpoint {
+                node,
+                peer,
+                latency: latency.clone(),
+                buffer: VecDeque::new(),
+                eof: false,
+                dropped: false,
+                last: Duration::from_secs(0),
+            });
+        }
+        let (an, bn) = (state.nodes[a.0].name.clone(), state.nodes[b.0].name.clone());
+        let end = |endpoint, node| Box::new(End { shared: self.shared.clone(), endpoint, node });
+        (Channel(Some(bn), end(i, a), None, false), Channel(Some(an), end(j, b), None, false))
+    }
+
+    /// Cut the link between two nodes from time `from` until `to`. Messages
+    /// sent meanwhile are held, and arrive once the partition heals.
+    pub fn partition(&mut self, a: Node, b: Node, from: Duration, to: Option<Duration>) {
+        let mut state = self.shared.lock();
+        state.schedule(from, Event::Partition(a, b));
+        if let Some(to) = to {
+            state.schedule(to, Event::Heal(a, b));
+        }
+    }
+
+    /// Run `f` as the code of `node`.
+    pub fn spawn<F: FnOnce() + Send + 'static>(&mut self, node: Node, f: F) {
+        self.shared.lock().nodes[node.0].status = Status::Runnable;
+        self.tasks.push((node, Box::new(f)));
+    }
+
+    /// A handle to the network's virtual clock.
+    pub fn clock(&self) -> Clock {
+        Clock(self.shared.clone())
+    }
+
+    /// Run every node to completion, returning how much virtual time passed.
+    ///
+    /// Panics if any node panicked.
+    pub fn run(self) -> Duration {
+        let Network { shared, tasks } = self;
+        let threads = tasks.into_iter().map(|(node, f)| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                drop(shared.wait_turn(shared.lock(), node));
+                let result = panic::catch_unwind(AssertUnwindSafe(f));
+                let mut state = shared.lock();
+                state.nodes[node.0].status = Status::Done;
+                state.nodes[node.0].panicked = result.is_err();
+                state.pick();
+                shared.turn.notify_all();
+            })
+        }).collect::<Vec<_>>();
+        {
+            let mut state = shared.lock();
+            state.pick();
+            shared.turn.notify_all();
+        }
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let state = shared.lock();
+        if let Some(node) = state.nodes.iter().find(|n| n.panicked) {
+            panic!("node {} panicked", node.name);
+        }
+        state.now
+    }
+}
+
+/// The virtual clock of a simulated network.
+#[derive(Clone)]
+pub struct Clock(Arc<Shared>);
+
+impl Clock {
+    pub fn now(&self) -> Duration {
+        self.0.lock().now
+    }
+
+    /// Sleep the running node for `duration` of virtual time.
+    pub fn sleep(&self, duration: Duration) {
+        let mut state = self.0.lock();
+        let me = state.running.expect("sleep outside a simulated node");
+        let deadline = state.now + duration;
+        state.schedule(deadline, Event::Wake(me));
+        while state.now < deadline {
+            state.nodes[me.0].status = Status::Blocked;
+            state = self.0.yield_now(state, me);
+        }
+    }
+}
+
+/// A channel's end of a simulated link.
+struct End {
+    shared: Arc<Shared>,
+    endpoint: usize,
+    node: Node,
+}
+
+impl End {
+    fn check<'a>(&self, state: MutexGuard<'a, State>) -> MutexGuard<'a, State> {
+        if state.running != Some(self.node) {
+            let name = state.nodes[self.node.0].name.clone();
+            drop(state);
+            panic!("channel of node {} used outside of it", name);
+        }
+        state
+    }
+
+    fn ready(&self, state: &State) -> bool {
+        let endpoint = &state.endpoints[self.endpoint];
+        !endpoint.buffer.is_empty() || endpoint.eof || state.nodes[self.node.0].stuck
+    }
+}
+
+fn stuck() -> Error {
+    Error::new(ErrorKind::TimedOut, "simulated network is stuck")
+}
+
+impl Read for End {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut state = self.check(self.shared.lock());
+        loop {
+            let endpoint = &mut state.endpoints[self.endpoint];
+            if !endpoint.buffer.is_empty() {
+                let n = buf.len().min(endpoint.buffer.len());
+                for (b, byte) in buf.iter_mut().zip(endpoint.buffer.drain(..n)) {
+                    *b = byte;
+                }
+                return Ok(n);
+            }
+            if endpoint.eof {
+                return Ok(0);
+            }
+            if state.nodes[self.node.0].stuck {
+                state.nodes[self.node.0].stuck = false;
+                return Err(stuck());
+            }
+            state.nodes[self.node.0].status = Status::Blocked;
+            state = self.shared.yield_now(state, self.node);
+        }
+    }
+}
+
+impl Write for End {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        let mut state = self.check(self.shared.lock());
+        let peer = state.endpoints[self.endpoint].peer;
+        if state.endpoints[peer].dropped {
+            return Err(Error::new(ErrorKind::BrokenPipe, "simulated link closed"));
+        }
+        state.transmit(peer, Payload::Data(buf.to_vec()));
+        state.nodes[self.node.0].status = Status::Runnable;
+        drop(self.shared.yield_now(state, self.node));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for End {
+    fn is_ready(&self) -> Result<bool, Error> {
+        let state = self.check(self.shared.lock());
+        Ok(self.ready(&state))
+    }
+
+    /// Waits in virtual time, letting the other nodes run meanwhile.
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        let mut state = self.check(self.shared.lock());
+        let deadline = state.now + timeout;
+        if !self.ready(&state) && !timeout.is_zero() {
+            state.schedule(deadline, Event::Wake(self.node));
+        }
+        while !self.ready(&state) && state.now < deadline {
+            state.nodes[self.node.0].status = Status::Blocked;
+            state = self.shared.yield_now(state, self.node);
+        }
+        Ok(self.ready(&state))
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "simulated transport"))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "simulated transport"))
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        let mut state = self.shared.lock();
+        if !state.endpoints[self.endpoint].dropped {
+            state.endpoints[self.endpoint].dropped = true;
+            let peer = state.endpoints[self.endpoint].peer;
+            state.transmit(peer, Payload::Eof);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for End {
+    fn drop(&mut self) {
+        self.shutdown().ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn latency() {
+        let mut net = Network::new(0);
+        let (alice, bob) = (net.node("alice"), net.node("bob"));
+        let (mut a, mut b) = net.link(alice, bob, ms(10)..ms(10));
+        net.spawn(alice, move || {
+            a.send(&1u64).unwrap();
+            assert_eq!(2u64, a.recv::<u64>().unwrap());
+        });
+        net.spawn(bob, move || {
+            assert_eq!("alice", b.info());
+            let n: u64 = b.recv().unwrap();
+            b.send(&(n + 1)).unwrap();
+        });
+        assert_eq!(ms(20), net.run());
+    }
+
+    /// Two nodes race to send to a third, returning the order it saw.
+    fn race(seed: u64) -> Vec<String> {
+        let mut net = Network::new(seed);
+        let (a, b, c) = (net.node("a"), net.node("b"), net.node("c"));
+        let (mut a, ca) = net.link(a, c, ms(1)..ms(10));
+        let (mut b, cb) = net.link(b, c, ms(1)..ms(10));
+        let seen = Arc::new(Mutex::new(vec![]));
+        net.spawn(Node(0), move || for i in 0..5 { a.send(&format!("a{}", i)).unwrap() });
+        net.spawn(Node(1), move || for i in 0..5 { b.send(&format!("b{}", i)).unwrap() });
+        let s = seen.clone();
+        net.spawn(Node(2), move || {
+            let mut open = vec![ca, cb];
+            while !open.is_empty() {
+                for i in (0..open.len()).rev() {
+                    if open[i].wait_ready(ms(1)).unwrap() {
+                        match open[i].recv::<String>() {
+                            Ok(m) => s.lock().unwrap().push(m),
+                            Err(_) => drop(open.remove(i)),
+                        }
+                    }
+                }
+            }
+        });
+        net.run();
+        let seen = seen.lock().unwrap().clone();
+        seen
+    }
+
+    #[test]
+    fn deterministic() {
+        assert_eq!(race(1337), race(1337));
+        assert_eq!(10, race(1337).len());
+        let orders = (0..10).map(race).collect::<HashSet<_>>();
+        assert!(orders.len() > 1);
+    }
+
+    #[test]
+    fn partition_heals() {
+        let mut net = Network::new(0);
+        let clock = net.clock();
+        let (alice, bob) = (net.node("alice"), net.node("bob"));
+        let (mut a, mut b) = net.link(alice, bob, ms(1)..ms(1));
+        net.partition(alice, bob, ms(0), Some(ms(100)));
+        net.spawn(alice, move || a.send(&1u8).unwrap());
+        net.spawn(bob, move || {
+            assert_eq!(1u8, b.recv::<u8>().unwrap());
+            assert_eq!(ms(101), clock.now());
+        });
+        assert_eq!(ms(101), net.run());
+    }
+
+    #[test]
+    fn stuck() {
+        let mut net = Network::new(0);
+        let (alice, bob) = (net.node("alice"), net.node("bob"));
+        let (mut a, mut b) = net.link(alice, bob, ms(1)..ms(1));
+        net.partition(alice, bob, ms(0), None);
+        net.spawn(alice, move || {
+            a.send(&1u8).unwrap();
+            let error = a.recv::<u8>().unwrap_err();
+            assert!(error.to_string().contains("stuck"));
+        });
+        net.spawn(bob, move || assert!(b.recv::<u8>().is_err()));
+        net.run();
+    }
+
+    #[test]
+    fn sleep() {
+        let mut net = Network::new(0);
+        let clock = net.clock();
+        let alice = net.node("alice");
+        net.spawn(alice, move || clock.sleep(ms(500)));
+        assert_eq!(ms(500), net.run());
+    }
+
+    #[test]
+    fn wait_ready() {
+        let mut net = Network::new(0);
+        let clock = net.clock();
+        let (alice, bob) = (net.node("alice"), net.node("bob"));
+        let (mut a, b) = net.link(alice, bob, ms(100)..ms(100));
+        net.spawn(alice, move || a.send(&1u8).unwrap());
+        net.spawn(bob, move || {
+            assert!(!b.is_ready().unwrap());
+            assert_eq!(ms(0), clock.now());
+            assert!(!b.wait_ready(ms(50)).unwrap());
+            assert_eq!(ms(50), clock.now());
+            assert!(b.wait_ready(ms(100)).unwrap());
+            assert_eq!(ms(100), clock.now());
+        });
+        net.run();
+    }
+
+    #[test]
+    #[should_panic(expected = "node bob panicked")]
+    fn node_panics() {
+        let mut net = Network::new(0);
+        let (alice, bob) = (net.node("alice"), net.node("bob"));
+        let (mut a, b) = net.link(alice, bob, ms(1)..ms(1));
+        net.spawn(alice, move || assert!(a.recv::<u8>().is_err()));
+        net.spawn(bob, move || {
+            drop(b);
+            panic!("bob");
+        });
+        net.run();
+    }
+}
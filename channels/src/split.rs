@@ -0,0 +1,180 @@
+//! Reading and writing a channel from different threads.
+//!
+//! `Channel::split` gives a `ReadHalf` which can only `recv`, and a
+//! `WriteHalf` which can only `send`, each of which can be moved to its own
+//! thread. A recording channel's halves record to the same transcript.
+//!
+//! ```rust,ignore
+//! let (mut read, mut write) = channel.split()?;
+//! thread::spawn(move || loop { write.send(&telemetry())?; });
+//! let command: Command = read.recv()?;
+//! ```
+use std::error;
+use std::fmt::{self, Debug, Display};
+use std::io::Error;
+use std::sync::Arc;
+use serde::{Serialize, Deserialize};
+use crate::Channel;
+
+/// The receiving half of a channel.
+#[derive(Debug)]
+pub struct ReadHalf {
+    channel: Channel,
+    token: Arc<()>,
+}
+
+/// The sending half of a channel.
+#[derive(Debug)]
+pub struct WriteHalf {
+    channel: Channel,
+    token: Arc<()>,
+}
+
+/// Splitting and reuniting.
+impl Channel {
+    /// Split into halves which can be used independently. This fails if the
+    /// transport can't be cloned.
+    pub fn split(self) -> Result<(ReadHalf, WriteHalf), Error> {
+        let token = Arc::new(());
+        let write = Channel(self.0.clone(), self.1.try_clone()?, self.2.clone(), false);
+        Ok((ReadHalf { channel: self, token: token.clone() }, WriteHalf { channel: write, token }))
+    }
+
+    /// Put the halves of a channel back together.
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(read: ReadHalf, write: WriteHalf) -> Result<Channel, ReuniteError> {
+        if Arc::ptr_eq(&read.token, &write.token) {
+            Ok(read.channel)
+        } else {
+            Err(ReuniteError(read, write))
+        }
+    }
+}
+
+impl ReadHalf {
+    pub fn info(&self) -> &str {
+        self.channel.info()
+    }
+
+    pub fn recv<T>(&mut self) -> Result<T, Error>
+    where for<'de> T: Deserialize<'de> + Debug
+    {
+        self.channel.recv()
+    }
+
+    /// See `Channel::is_ready`.
+    pub fn is_ready(&self) -> Result<bool, Error> {
+        self.channel.is_ready()
+    }
+}
+
+impl WriteHalf {
+    pub fn info(&self) -> &str {
+        self.channel.info()
+    }
+
+    pub fn send<T: Serialize + Debug>(&mut self, message: &T) -> Result<(), Error> {
+        self.channel.send(message)
+    }
+}
+
+/// The halves given to `Channel::reunite` were from different channels.
+#[derive(Debug)]
+pub struct ReuniteError(pub ReadHalf, pub WriteHalf);
+
+impl Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "tried to reunite halves of different channels")
+    }
+}
+
+impl error::Error for ReuniteError {}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    #[test]
+    fn full_duplex() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (mut ar, mut aw) = a.split().unwrap();
+        let (mut br, mut bw) = b.split().unwrap();
+
+        // Both sides send everything before reading anything.
+        let t = thread::spawn(move || for i in 0..100u64 { aw.send(&i).unwrap() });
+        for i in 0..100u64 {
+            bw.send(&(i * 2)).unwrap();
+        }
+        for i in 0..100u64 {
+            assert_eq!(i, br.recv::<u64>().unwrap());
+            assert_eq!(i * 2, ar.recv::<u64>().unwrap());
+        }
+        t.join().unwrap();
+        assert_eq!("nixpulvis", ar.info());
+    }
+
+    #[test]
+    fn reunite() {
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (ar, aw) = a.split().unwrap();
+        let (br, bw) = b.split().unwrap();
+
+        let ReuniteError(ar, bw) = Channel::reunite(ar, bw).unwrap_err();
+        let ReuniteError(br, aw) = Channel::reunite(br, aw).unwrap_err();
+        let mut a = Channel::reunite(ar, aw).unwrap();
+        let mut b = Channel::reunite(br, bw).unwrap();
+        a.send(&1u8).unwrap();
+        assert_eq!(1u8, b.recv::<u8>().unwrap());
+
+        // The stream closes once every half is gone.
+        drop(a);
+        assert!(b.recv::<u8>().is_err());
+    }
+
+    #[test]
+    fn is_ready_while_sending() {
+        use std::net::{TcpListener, TcpStream};
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::{mpsc, Arc};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (finish, finished) = mpsc::channel();
+        let t = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut channel = Channel(None, Box::new(stream), None, false);
+            for i in 0..40u8 {
+                assert_eq!(vec![i; 1 << 16], channel.recv::<Vec<u8>>().unwrap());
+            }
+            // Hanging up would make the other end ready.
+            finished.recv().unwrap();
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        let (read, mut write) = Channel(None, Box::new(stream), None, false).split().unwrap();
+
+        // Checking readiness on one half mustn't disturb writes on the other.
+        let done = Arc::new(AtomicBool::new(false));
+        let d = done.clone();
+        let checker = thread::spawn(move || while !d.load(Ordering::SeqCst) {
+            assert!(!read.is_ready().unwrap());
+        });
+        for i in 0..40u8 {
+            write.send(&vec![i; 1 << 16]).unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+        checker.join().unwrap();
+        finish.send(()).unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn unsupported() {
+        use crate::fault::Faulty;
+        use crate::memory;
+
+        let (a, _b) = memory::pair();
+        let channel = Channel(None, Box::new(Faulty::new(a)), None, false);
+        assert!(channel.split().is_err());
+    }
+}
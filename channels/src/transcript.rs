@@ -0,0 +1,259 @@
+//! Recording and replaying the frames sent over a channel.
+//!
+//! A recording channel writes every whole message it sends or receives to a
+//! transcript, and `Replay` plays the other end of a transcript back, so a
+//! recorded run can be reproduced without the original peer.
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Serialize, Deserialize};
+use crate::Channel;
+use crate::transport::Transport;
+
+/// Which way a frame went, from the recording channel's point of view.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One whole message on the wire.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub direction: Direction,
+    /// Microseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub session: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Writes frames to a transcript.
+pub(crate) struct Recorder {
+    session: String,
+    out: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub(crate) fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<(), Error> {
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as u64)
+            .unwrap_or(0);
+        let frame = Frame {
+            direction,
+            timestamp,
+            session: self.session.clone(),
+            bytes: bytes.to_vec(),
+        };
+        bincode::serialize_into(&mut self.out, &frame).map_err(Error::other)?;
+        self.out.flush()
+    }
+}
+
+/// A reader which keeps a copy of everything read through it.
+pub(crate) struct Tee<'a, R: ?Sized>(pub &'a mut R, pub Vec<u8>);
+
+impl<'a, R: Read + ?Sized> Read for Tee<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let n = self.0.read(buf)?;
+        self.1.extend(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Transcript recording.
+impl Channel {
+    /// Record every frame from now on to `out`, tagged with `session`.
+    pub fn record<W: Write + Send + 'static>(&mut self, session: String, out: W) {
+        self.2 = Some(Arc::new(Mutex::new(Recorder { session, out: Box::new(out) })));
+    }
+
+    /// Stop recording frames.
+    pub fn stop_recording(&mut self) {
+        self.2 = None;
+    }
+
+    /// Create a channel which plays the other end of a recorded transcript.
+    pub fn replay(replay: Replay) -> Channel {
+        Channel(replay.session.clone(), Box::new(replay), None, false)
+    }
+}
+
+/// Read every frame from a transcript.
+pub fn read<R: Read>(mut transcript: R) -> Result<Vec<Frame>, Error> {
+    let mut frames = vec![];
+    loop {
+        match bincode::deserialize_from(&mut transcript) {
+            Ok(frame) => frames.push(frame),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                    return Ok(frames);
+                }
+                _ => return Err(Error::other(e)),
+            },
+        }
+    }
+}
+
+/// A transport playing the peer of a recording channel.
+///
+/// Reads return the frames the recording channel received, and writes must
+/// match the frames it sent, in the same order. Anything else is an error,
+/// since the code using the replay has diverged from the recording.
+pub struct Replay {
+    session: Option<String>,
+    frames: VecDeque<Frame>,
+    offset: usize,
+}
+
+impl Replay {
+    /// Replay the frames of one session from a transcript.
+    pub fn new<I: IntoIterator<Item = Frame>>(session: &str, frames: I) -> Replay {
+        Replay {
+            session: Some(session.into()),
+            frames: frames.into_iter().filter(|f| f.session == session).collect(),
+            offset: 0,
+        }
+    }
+
+    /// Move on to the next frame once this one's been used up.
+    fn advance(&mut self) {
+        if self.frames.front().map(|f| f.bytes.len()) == Some(self.offset) {
+            self.frames.pop_front();
+            self.offset = 0;
+        }
+    }
+}
+
+fn diverged(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("transcript diverged: {}", message))
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.frames.front() {
+            None => Ok(0),
+            Some(f) if f.direction == Direction::Sent => {
+                Err(diverged("received before sending"))
+            }
+            Some(f) => {
+                let n = buf.len().min(f.bytes.len() - self.offset);
+                buf[..n].copy_from_slice(&f.bytes[self.offset..self.offset + n]);
+                self.offset += n;
+                self.advance();
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.frames.front() {
+            None => Err(diverged("sent after the end")),
+            Some(f) if f.direction == Direction::Received => {
+                Err(diverged("sent before receiving"))
+            }
+            Some(f) => {
+                let n = buf.len().min(f.bytes.len() - self.offset);
+                if buf[..n] != f.bytes[self.offset..self.offset + n] {
+                    return Err(diverged("sent different bytes"));
+                }
+                self.offset += n;
+                self.advance();
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Transport for Replay {
+    fn is_ready(&self) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "replay transport"))
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "replay transport"))
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use super::*;
+
+    /// A transcript file kept in memory.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn record() -> Vec<Frame> {
+        let transcript = Shared::default();
+        let (mut a, mut b) = Channel::memory_pair("nixpulvis".into());
+        a.record("session-1".into(), transcript.clone());
+        let t = thread::spawn(move || {
+            let n: u64 = b.recv().unwrap();
+            b.send(&format!("got {}", n)).unwrap();
+        });
+        a.send(&42u64).unwrap();
+        assert_eq!("got 42", a.recv::<String>().unwrap());
+        t.join().unwrap();
+        let bytes = transcript.0.lock().unwrap().clone();
+        read(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn record_frames() {
+        let frames = record();
+        assert_eq!(2, frames.len());
+        assert_eq!(Direction::Sent, frames[0].direction);
+        assert_eq!(bincode::serialize(&(crate::Frame::Data, 42u64)).unwrap(), frames[0].bytes);
+        assert_eq!(Direction::Received, frames[1].direction);
+        assert_eq!(bincode::serialize(&(crate::Frame::Data, "got 42")).unwrap(), frames[1].bytes);
+        assert!(frames.iter().all(|f| f.session == "session-1"));
+        assert!(frames[0].timestamp <= frames[1].timestamp);
+    }
+
+    #[test]
+    fn replay() {
+        let mut c = Channel::replay(Replay::new("session-1", record()));
+        c.send(&42u64).unwrap();
+        assert_eq!("got 42", c.recv::<String>().unwrap());
+        assert!(c.recv::<String>().is_err());
+    }
+
+    #[test]
+    fn replay_diverges() {
+        let mut c = Channel::replay(Replay::new("session-1", record()));
+        let error = c.send(&7u64).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, error.kind());
+
+        let mut c = Channel::replay(Replay::new("session-1", record()));
+        assert!(c.recv::<String>().is_err());
+
+        let mut c = Channel::replay(Replay::new("session-2", record()));
+        assert!(c.send(&42u64).is_err());
+    }
+}
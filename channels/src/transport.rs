@@ -0,0 +1,158 @@
+//! Byte streams a `Channel` can be built on.
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A bidirectional, reliable and ordered stream of bytes.
+///
+/// Reads block until there's data, and return `Ok(0)` once the other end has
+/// closed the stream.
+pub trait Transport: Read + Write + Send {
+    /// Returns true when a read would not block.
+    fn is_ready(&self) -> Result<bool, Error>;
+
+    /// Wait up to `timeout` for a read not to block, returning whether it
+    /// won't. By default this checks `is_ready` every millisecond, transports
+    /// which can wait for data do that instead.
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        let until = Instant::now() + timeout;
+        loop {
+            if self.is_ready()? {
+                return Ok(true);
+            }
+            let left = until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Ok(false);
+            }
+            thread::sleep(left.min(Duration::from_millis(1)));
+        }
+    }
+
+    /// The file descriptor to poll for readiness, if the transport's just a
+    /// socket, so many can be waited on at once.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Our address, if the transport has one.
+    fn local_addr(&self) -> Result<SocketAddr, Error>;
+
+    /// Their address, if the transport has one.
+    fn peer_addr(&self) -> Result<SocketAddr, Error>;
+
+    /// Close the stream in both directions.
+    fn shutdown(&self) -> Result<(), Error>;
+
+    /// Another handle to the same stream, so one thread can read while
+    /// another writes. The stream stays open until every handle is dropped.
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        Err(Error::new(ErrorKind::Unsupported, "transport can't be cloned"))
+    }
+}
+
+/// Wait up to `timeout` milliseconds (-1 for ever) for one of the sockets to
+/// be readable, closed or failed, returning the first which is. Unlike a
+/// nonblocking `peek` this leaves the sockets' blocking mode alone, which is
+/// shared with their clones.
+#[cfg(unix)]
+fn poll(fds: &[RawFd], timeout: libc::c_int) -> Result<Option<usize>, Error> {
+    let mut fds = fds.iter()
+        .map(|fd| libc::pollfd { fd: *fd, events: libc::POLLIN, revents: 0 })
+        .collect::<Vec<_>>();
+    loop {
+        // Safe, `fds` are valid pollfds for the duration of the call.
+        match unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } {
+            -1 => {
+                let e = Error::last_os_error();
+                if e.kind() != ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+            _ => return Ok(fds.iter().position(|fd| fd.revents != 0)),
+        }
+    }
+}
+
+/// How long `wait_any` waits on each transport in turn, when it can't wait on
+/// them all at once.
+const WAIT_ANY_TURN: Duration = Duration::from_millis(1);
+
+/// Block until one of the transports is ready, returning the first which is.
+/// Sockets are all waited on at once, anything else is waited on in turn.
+pub(crate) fn wait_any(transports: &[&dyn Transport]) -> Result<usize, Error> {
+    loop {
+        for (i, t) in transports.iter().enumerate() {
+            if t.is_ready()? {
+                return Ok(i);
+            }
+        }
+        #[cfg(unix)]
+        {
+            let fds = transports.iter().map(|t| t.raw_fd()).collect::<Option<Vec<_>>>();
+            if let Some(fds) = fds {
+                if let Some(i) = poll(&fds, -1)? {
+                    return Ok(i);
+                }
+                continue;
+            }
+        }
+        for (i, t) in transports.iter().enumerate() {
+            if t.wait_ready(WAIT_ANY_TURN)? {
+                return Ok(i);
+            }
+        }
+    }
+}
+
+impl Transport for TcpStream {
+    #[cfg(unix)]
+    fn is_ready(&self) -> Result<bool, Error> {
+        Ok(poll(&[self.as_raw_fd()], 0)?.is_some())
+    }
+
+    /// Without poll, peek without blocking. Streams are otherwise always
+    /// blocking, so that's what this leaves them.
+    #[cfg(not(unix))]
+    fn is_ready(&self) -> Result<bool, Error> {
+        self.set_nonblocking(true)?;
+        let peeked = self.peek(&mut [0]);
+        self.set_nonblocking(false)?;
+        match peeked {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(_) => Ok(true),
+        }
+    }
+
+    #[cfg(unix)]
+    fn wait_ready(&self, timeout: Duration) -> Result<bool, Error> {
+        // Round up, so a short wait doesn't become no wait at all.
+        let ms = timeout.as_micros().div_ceil(1000);
+        Ok(poll(&[self.as_raw_fd()], ms.min(libc::c_int::MAX as u128) as libc::c_int)?.is_some())
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+
+    fn local_addr(&self) -> Result<SocketAddr, Error> {
+        TcpStream::local_addr(self)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr, Error> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn shutdown(&self) -> Result<(), Error> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>, Error> {
+        Ok(Box::new(TcpStream::try_clone(self)?))
+    }
+}
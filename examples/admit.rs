@@ -1,4 +1,5 @@
 use session_types::*;
+use std::env;
 use std::io::{self, Write};
 
 // Type of an admittance protocol.
@@ -19,13 +20,13 @@ fn admittor(c: Chan<(), Admittance>) {
     let (c, id) = c.recv();
     // Leet tickets get in for a long time.
     if id == 1337 {
-        c.sel0().send(1337).close();
+        c.sel0().send(1337).close().unwrap();
     // Let in anyone mod 3 (~1/3 of requests).
     } else if id % 3 == 0 {
-        c.sel0().send(100).close();
+        c.sel0().send(100).close().unwrap();
     // Everyone else we just ignore at this point.
     } else {
-        c.sel1().close();
+        c.sel1().close().unwrap();
     }
 }
 
@@ -47,9 +48,12 @@ fn client(c: Chan<(), Client>) {
                 expire
             );
             // We're done.
-            c.close();
+            c.close().unwrap();
+        }
+        Branch::Right(c) => {
+            println!("\nDAMN IT!\n");
+            c.close().unwrap();
         }
-        Branch::Right(_) => println!("\nDAMN IT!\n"),
     };
 }
 
@@ -61,12 +65,28 @@ fn read_input(m: &str) -> u64 {
         .read_line(&mut input_text)
         .expect("failed to read from stdin");
     match input_text.trim().parse::<u64>() {
-        Ok(i) => return i,
+        Ok(i) => i,
         Err(_) => panic!("not given valid u64"),
-    };
+    }
 }
 
 fn main() {
+    // Print the protocol instead of running it.
+    if env::args().any(|a| a == "--diagram") {
+        print!("{}", diagram::mermaid::<Admittance>("Admittor", "Client"));
+        return;
+    }
     connect(admittor, client);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use session_types::check::check;
+    use super::*;
+
+    #[test]
+    fn check_admittor() {
+        check(100, admittor);
+    }
+}
@@ -51,7 +51,7 @@ pub fn sender(m: (BigInt, BigInt), c: Chan<(), OT>) {
                     (v.clone()-x1 % &n).modpow(&d, &n));
     // She combines the two secret messages with each of the possible keys, m0
     // and m1 and sends them both to Bob.
-    c.send((m0+k0, m1+k1)).close();
+    c.send((m0+k0, m1+k1)).close().unwrap();
 }
 
 // OT's complement protocol for the receiver (Bob).
@@ -74,35 +74,50 @@ pub fn receiver<C>(chooser: C, c: Chan<(), <OT as Dual>::Dual>)
     // Bob knows which of the two messages can be un-blinded with k, so he
     // is able to compute exactly one of the messages mb.
     let mb = match b { Left => (m0 - k) % &n, Right => (m1 - k) % &n };
-    c.close();
-    return mb;
+    c.close().unwrap();
+    mb
 }
 
 #[cfg(test)]
 mod tests {
-    use std::marker::PhantomData;
     use std::thread;
     use std::time::Duration;
-    use channels::Channel;
+    use session_types::check::check;
+    use session_types::mock::Script;
     use super::*;
 
     #[test]
     fn oblivious_transfer() {
         let addr = "127.0.0.1:2200";
 
-        thread::spawn(move || {
-            let c = Channel::accept_from_socket_addr(addr).unwrap();
-            let ch = Chan(c, PhantomData);
+        let r = thread::spawn(move || {
+            let ch = Chan::accept(addr).unwrap();
             let mb = receiver(|_,_| { Choice::Left }, ch);
-            assert_eq!(mb, BigInt::from(11357));
-            assert!(false); // TODO: Why isn't this hit?
+            assert_eq!(mb, BigInt::from(1357));
         });
         thread::sleep(Duration::from_millis(10));
         thread::spawn(move || {
             let identity = "nixpulvis".to_string();
-            let c = Channel::connect_to_socket_addr(identity, addr).unwrap();
-            let ch = Chan(c, PhantomData);
+            let ch = Chan::connect(addr, identity).unwrap();
             sender((BigInt::from(1357), BigInt::from(51687)), ch);
         }).join().unwrap();
+        r.join().unwrap();
+    }
+
+    #[test]
+    fn sender_follows_protocol() {
+        let (c, mock) = Script::<<OT as Dual>::Dual>::new()
+            .recv()
+            .send(BigInt::from(1337))
+            .recv()
+            .spawn();
+        sender((BigInt::from(1357), BigInt::from(51687)), c);
+        mock.join().unwrap();
+    }
+
+    #[test]
+    fn check_sender() {
+        // Each run makes an RSA key, so only a few.
+        check(5, |c| sender((BigInt::from(1357), BigInt::from(51687)), c));
     }
 }
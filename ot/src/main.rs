@@ -1,17 +1,15 @@
 use std::env;
 use std::io::{self, prelude::*, BufRead};
-use std::marker::PhantomData;
 use num::bigint::BigInt;
 use docopt::Docopt;
-use channels::Channel;
 use session_types::Chan;
 use ot::{Choice, sender, receiver};
 
-// const USAGE: &'static str = "
+// const USAGE: &str = "
 // Usage: ot --receiver [<choice>]
 //        ot --sender [<offers>]
 // ";
-const USAGE: &'static str = "
+const USAGE: &str = "
 Usage: ot --receiver
        ot --sender
 ";
@@ -22,17 +20,15 @@ fn main() {
 
     let addr = "127.0.0.1:1337";
     let args = Docopt::new(USAGE)
-        .and_then(|d| d.argv(env::args().into_iter()).parse())
+        .and_then(|d| d.argv(env::args()).parse())
         .unwrap_or_else(|e| e.exit());
 
     if args.get_bool("--sender") {
         let identity = "nixpulvis".to_string();
-        let c = Channel::connect_to_socket_addr(identity, addr).unwrap();
-        let ch = Chan(c, PhantomData);
+        let ch = Chan::connect(addr, identity).unwrap();
         sender(read_choices(), ch);
     } else if args.get_bool("--receiver") {
-        let c = Channel::accept_from_socket_addr(addr).unwrap();
-        let ch = Chan(c, PhantomData);
+        let ch = Chan::accept(addr).unwrap();
         let mb = receiver(|_,_| read_choice(), ch);
         println!("Bob got: {}", mb);
     }
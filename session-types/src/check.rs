@@ -0,0 +1,268 @@
+//! Property based testing of session typed code.
+//!
+//! `check` runs one end of a protocol many times against a randomly generated
+//! counterpart. The counterpart always follows the protocol, but takes random
+//! branches and sends arbitrary payloads, so it exercises paths hand written
+//! tests tend to miss. Failing runs report the seed they were generated from,
+//! which `check_seed` replays.
+//!
+//! ```rust,ignore
+//! check::<Admittance, _>(100, admittor);
+//! ```
+use std::io::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use channels::Channel;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use super::*;
+
+/// Types which can be randomly generated as payloads.
+pub trait Arbitrary: Sized {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self;
+}
+
+macro_rules! arbitrary_standard {
+    ($($t:ty),*) => {
+        $(impl Arbitrary for $t {
+            fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+                rng.gen()
+            }
+        })*
+    }
+}
+
+arbitrary_standard!(bool, char, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+impl Arbitrary for () {
+    fn arbitrary<R: Rng>(_: &mut R) -> Self {}
+}
+
+impl Arbitrary for String {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let len = rng.gen_range(0..16);
+        (0..len).map(|_| rng.gen::<char>()).collect()
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Option<T> {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        if rng.gen() { Some(T::arbitrary(rng)) } else { None }
+    }
+}
+
+impl<T: Arbitrary> Arbitrary for Vec<T> {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let len = rng.gen_range(0..16);
+        (0..len).map(|_| T::arbitrary(rng)).collect()
+    }
+}
+
+#[cfg(feature = "num")]
+impl Arbitrary for num::BigInt {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        num::BigInt::from_signed_bytes_le(&Vec::<u8>::arbitrary(rng))
+    }
+}
+
+#[cfg(feature = "num")]
+impl Arbitrary for num::BigUint {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        num::BigUint::from_bytes_le(&Vec::<u8>::arbitrary(rng))
+    }
+}
+
+impl<A: Arbitrary, B: Arbitrary> Arbitrary for (A, B) {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        (A::arbitrary(rng), B::arbitrary(rng))
+    }
+}
+
+impl<A: Arbitrary, B: Arbitrary, C: Arbitrary> Arbitrary for (A, B, C) {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        (A::arbitrary(rng), B::arbitrary(rng), C::arbitrary(rng))
+    }
+}
+
+/// How a counterpart's run of a protocol ended.
+pub enum Next {
+    /// The session is over.
+    Done,
+    /// Jump back to the `n`th enclosing `Rec`.
+    Continue(usize),
+}
+
+/// Protocols a random counterpart can play.
+pub trait Counterpart {
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error>;
+}
+
+impl Counterpart for Eps {
+    fn play<R: Rng>(_: &mut Channel, _: &mut R) -> Result<Next, Error> {
+        Ok(Next::Done)
+    }
+}
+
+impl<T, P> Counterpart for Send<T, P>
+where T: Arbitrary + Serialize + Debug,
+      P: Counterpart,
+{
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error> {
+        c.send(&T::arbitrary(rng))?;
+        P::play(c, rng)
+    }
+}
+
+impl<T, P> Counterpart for Recv<T, P>
+where T: for<'de> Deserialize<'de> + Debug,
+      P: Counterpart,
+{
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error> {
+        c.recv::<T>()?;
+        P::play(c, rng)
+    }
+}
+
+impl<P: Counterpart, Q: Counterpart> Counterpart for Choose<P, Q> {
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error> {
+        let left = rng.gen();
+        c.send(&left)?;
+        if left { P::play(c, rng) } else { Q::play(c, rng) }
+    }
+}
+
+impl<P: Counterpart, Q: Counterpart> Counterpart for Offer<P, Q> {
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error> {
+        if c.recv()? { P::play(c, rng) } else { Q::play(c, rng) }
+    }
+}
+
+impl<P: Counterpart> Counterpart for Rec<P> {
+    fn play<R: Rng>(c: &mut Channel, rng: &mut R) -> Result<Next, Error> {
+        loop {
+            match P::play(c, rng)? {
+                Next::Continue(0) => continue,
+                Next::Continue(n) => return Ok(Next::Continue(n - 1)),
+                Next::Done => return Ok(Next::Done),
+            }
+        }
+    }
+}
+
+impl Counterpart for Var<Z> {
+    fn play<R: Rng>(_: &mut Channel, _: &mut R) -> Result<Next, Error> {
+        Ok(Next::Continue(0))
+    }
+}
+
+/// Run `implementation` against `runs` random counterparts, panicking with
+/// the seed of the first run to fail.
+pub fn check<P, F>(runs: usize, implementation: F)
+where
+    P: Dual,
+    P::Dual: Counterpart,
+    F: Fn(Chan<(), P>),
+{
+    for _ in 0..runs {
+        check_seed(rand::random(), &implementation);
+    }
+}
+
+/// Run `implementation` against the random counterpart generated by `seed`.
+pub fn check_seed<P, F>(seed: u64, implementation: F)
+where
+    P: Dual,
+    P::Dual: Counterpart,
+    F: Fn(Chan<(), P>),
+{
+    info!("checking seed {}", seed);
+    let (ours, mut theirs) = Channel::memory_pair("check".into());
+    let counterpart = thread::spawn(move || {
+        let mut rng = StdRng::seed_from_u64(seed);
+        <P::Dual>::play(&mut theirs, &mut rng)?;
+        theirs.close()
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        implementation(Chan(ours, PhantomData))
+    }));
+    match (result, counterpart.join().unwrap()) {
+        (Ok(()), Ok(_)) => {}
+        (Ok(()), Err(e)) => panic!("counterpart failed with seed {}: {}", seed, e),
+        (Err(_), _) => panic!("implementation failed with seed {}", seed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Lookup = Recv<u64, Choose<Send<String, Eps>, Eps>>;
+
+    fn directory(c: Chan<(), Lookup>) {
+        let (c, n) = c.recv();
+        if n % 2 == 0 {
+            c.sel0().send(format!("#{}", n)).close().unwrap();
+        } else {
+            c.sel1().close().unwrap();
+        }
+    }
+
+    #[test]
+    fn check_directory() {
+        check(100, directory);
+    }
+
+    fn lookup(c: Chan<(), <Lookup as Dual>::Dual>) {
+        match c.send(2).offer() {
+            Branch::Left(c) => {
+                let (c, name) = c.recv();
+                // Not every directory answers what was asked.
+                assert_eq!("#2", name);
+                c.close().unwrap();
+            }
+            Branch::Right(c) => c.close().unwrap(),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "implementation failed with seed")]
+    fn check_lookup() {
+        check(100, lookup);
+    }
+
+    #[test]
+    fn check_loop() {
+        type Sum = Rec<Offer<Send<u64, Eps>, Recv<u8, Var<Z>>>>;
+        check(100, |c: Chan<(), Sum>| {
+            let mut c = c.enter();
+            let mut sum = 0;
+            loop {
+                c = match c.offer() {
+                    Branch::Left(c) => {
+                        c.send(sum).close().unwrap();
+                        break;
+                    }
+                    Branch::Right(c) => {
+                        let (c, n) = c.recv();
+                        sum += n as u64;
+                        c.zero()
+                    }
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn replay_seed() {
+        let seen = std::cell::RefCell::new(vec![]);
+        for _ in 0..2 {
+            check_seed(1337, |c: Chan<(), Recv<Vec<u8>, Eps>>| {
+                let (c, v) = c.recv();
+                seen.borrow_mut().push(v);
+                c.close().unwrap();
+            });
+        }
+        let seen = seen.into_inner();
+        assert_eq!(seen[0], seen[1]);
+    }
+}
@@ -0,0 +1,241 @@
+//! Value level descriptions of session types.
+//!
+//! The protocol types in this crate only exist at compile time, which is
+//! exactly what we want for checking programs, but not much help for anything
+//! that wants to look at a protocol, like drawing it. `Describe` reflects a
+//! protocol type into a `Session` tree which can be inspected at runtime.
+use super::*;
+
+/// A session type as a plain old value.
+///
+/// Payloads are recorded by their `SessionName`, and variables by their de
+/// Bruijn index (`Var<Z>` is `Var(0)`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Session {
+    Eps,
+    Send(String, Box<Session>),
+    Recv(String, Box<Session>),
+    Offer(Box<Session>, Box<Session>),
+    Choose(Box<Session>, Box<Session>),
+    Rec(Box<Session>),
+    Var(usize),
+}
+
+impl Session {
+    /// The session type of the other end of the channel.
+    pub fn dual(&self) -> Session {
+        match self {
+            Session::Eps => Session::Eps,
+            Session::Send(t, p) => Session::Recv(t.clone(), Box::new(p.dual())),
+            Session::Recv(t, p) => Session::Send(t.clone(), Box::new(p.dual())),
+            Session::Offer(p, q) => Session::Choose(Box::new(p.dual()), Box::new(q.dual())),
+            Session::Choose(p, q) => Session::Offer(Box::new(p.dual()), Box::new(q.dual())),
+            Session::Rec(p) => Session::Rec(Box::new(p.dual())),
+            Session::Var(n) => Session::Var(*n),
+        }
+    }
+
+    /// A stable hash of this session type.
+    ///
+    /// This is a 64-bit FNV-1a hash of the session's structure and payload
+    /// names, so it's the same across processes and builds.
+    pub fn fingerprint(&self) -> u64 {
+        let mut bytes = vec![];
+        self.encode(&mut bytes);
+        bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+            (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Session::Eps => out.push(b'e'),
+            Session::Send(t, p) | Session::Recv(t, p) => {
+                out.push(if let Session::Send(..) = self { b's' } else { b'r' });
+                out.extend(t.as_bytes());
+                out.push(0);
+                p.encode(out);
+            }
+            Session::Offer(p, q) | Session::Choose(p, q) => {
+                out.push(if let Session::Offer(..) = self { b'o' } else { b'c' });
+                p.encode(out);
+                q.encode(out);
+            }
+            Session::Rec(p) => {
+                out.push(b'R');
+                p.encode(out);
+            }
+            Session::Var(n) => {
+                out.push(b'V');
+                out.extend(&(*n as u64).to_le_bytes());
+            }
+        }
+    }
+}
+
+/// Payload types, with a canonical name.
+///
+/// Protocols are described, and so fingerprinted, by these names, so two
+/// types with the same name are the same type as far as a session's
+/// concerned. Only the name is covered, not the type's fields: changing a
+/// struct's layout without renaming it keeps the same fingerprint, and so
+/// isn't caught when a session starts. Implement it with `session_name!`, or
+/// by hand for generic types, naming them the way `std::any::type_name`
+/// does.
+pub trait SessionName {
+    fn session_name() -> String;
+}
+
+/// Implement `SessionName` for types, named by their full path as given by
+/// `std::any::type_name`, so it doesn't matter how the type was imported.
+/// The compiler doesn't promise that name won't change between its versions,
+/// though in practice it's the path, and moving a type to another module
+/// changes it.
+///
+/// ```rust,ignore
+/// session_types::session_name!(Ticket, Receipt);
+/// ```
+#[macro_export]
+macro_rules! session_name {
+    ($($t:ty),* $(,)?) => {
+        $(impl $crate::describe::SessionName for $t {
+            fn session_name() -> String {
+                ::std::any::type_name::<$t>().into()
+            }
+        })*
+    }
+}
+
+session_name!(bool, char, u8, u16, u32, u64, u128, usize,
+              i8, i16, i32, i64, i128, isize, f32, f64, String, ());
+
+#[cfg(feature = "num")]
+session_name!(num::BigInt, num::BigUint);
+
+impl<T: SessionName> SessionName for Option<T> {
+    fn session_name() -> String {
+        format!("core::option::Option<{}>", T::session_name())
+    }
+}
+
+impl<T: SessionName> SessionName for Vec<T> {
+    fn session_name() -> String {
+        format!("alloc::vec::Vec<{}>", T::session_name())
+    }
+}
+
+impl<T: SessionName> SessionName for Box<T> {
+    fn session_name() -> String {
+        format!("alloc::boxed::Box<{}>", T::session_name())
+    }
+}
+
+macro_rules! session_name_tuple {
+    ($($t:ident),*) => {
+        impl<$($t: SessionName),*> SessionName for ($($t,)*) {
+            fn session_name() -> String {
+                format!("({})", [$($t::session_name()),*].join(", "))
+            }
+        }
+    }
+}
+
+session_name_tuple!(A, B);
+session_name_tuple!(A, B, C);
+session_name_tuple!(A, B, C, D);
+
+/// Protocol types which can be reflected into a `Session`.
+pub trait Describe {
+    fn describe() -> Session;
+}
+
+impl Describe for Eps {
+    fn describe() -> Session {
+        Session::Eps
+    }
+}
+
+impl<T: SessionName, P: Describe> Describe for Send<T,P> {
+    fn describe() -> Session {
+        Session::Send(T::session_name(), Box::new(P::describe()))
+    }
+}
+
+impl<T: SessionName, P: Describe> Describe for Recv<T,P> {
+    fn describe() -> Session {
+        Session::Recv(T::session_name(), Box::new(P::describe()))
+    }
+}
+
+impl<P: Describe, Q: Describe> Describe for Offer<P,Q> {
+    fn describe() -> Session {
+        Session::Offer(Box::new(P::describe()), Box::new(Q::describe()))
+    }
+}
+
+impl<P: Describe, Q: Describe> Describe for Choose<P,Q> {
+    fn describe() -> Session {
+        Session::Choose(Box::new(P::describe()), Box::new(Q::describe()))
+    }
+}
+
+impl<P: Describe> Describe for Rec<P> {
+    fn describe() -> Session {
+        Session::Rec(Box::new(P::describe()))
+    }
+}
+
+impl Describe for Var<Z> {
+    fn describe() -> Session {
+        Session::Var(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Ping = Rec<Offer<Eps, Recv<u8, Send<String, Var<Z>>>>>;
+
+    #[test]
+    fn describe() {
+        let expected = Session::Rec(Box::new(Session::Offer(
+            Box::new(Session::Eps),
+            Box::new(Session::Recv("u8".into(), Box::new(
+                Session::Send("alloc::string::String".into(), Box::new(Session::Var(0)))))))));
+        assert_eq!(expected, Ping::describe());
+    }
+
+    #[test]
+    fn dual() {
+        type Pong = Rec<Choose<Eps, Send<u8, Recv<String, Var<Z>>>>>;
+        assert_eq!(Pong::describe(), Ping::describe().dual());
+        assert_eq!(Ping::describe(), Ping::describe().dual().dual());
+    }
+
+    #[test]
+    fn fingerprint() {
+        type Pong = Rec<Choose<Eps, Send<u8, Recv<String, Var<Z>>>>>;
+        assert_eq!(Pong::describe().fingerprint(), Ping::describe().dual().fingerprint());
+        assert_ne!(Pong::describe().fingerprint(), Ping::describe().fingerprint());
+        assert_ne!(Send::<u8, Eps>::describe().fingerprint(),
+                   Send::<u16, Eps>::describe().fingerprint());
+        // The hash is part of the wire protocol, so it must never change.
+        assert_eq!(0xaf63_d84c_8601_e5c0, Session::Eps.fingerprint());
+        assert_eq!(0xc74e_c784_ee35_c2ca, Recv::<(u64, Vec<String>), Eps>::describe().fingerprint());
+    }
+
+    mod tickets {
+        pub struct Ticket;
+    }
+
+    session_name!(self::tickets::Ticket);
+
+    #[test]
+    fn session_name() {
+        assert_eq!("session_types::describe::tests::tickets::Ticket", tickets::Ticket::session_name());
+        // Generic types are named the same way.
+        type Payload = (u64, Option<Vec<String>>, Box<()>);
+        assert_eq!(std::any::type_name::<Payload>(), Payload::session_name());
+    }
+}
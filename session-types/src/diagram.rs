@@ -0,0 +1,270 @@
+//! Protocol diagrams generated from session types.
+//!
+//! Both renderers take the session type of one end of the channel, and the
+//! names to give each end. For example the `Admittance` protocol from the
+//! `admit` example can be drawn with:
+//!
+//! ```rust,ignore
+//! println!("{}", diagram::mermaid::<Admittance>("Admittor", "Client"));
+//! ```
+use std::fmt::Write;
+use super::describe::{Describe, Session};
+
+/// Render a Mermaid sequence diagram of the protocol `P`, as seen from `us`.
+///
+/// Messages are drawn as arrows, branches as `alt` blocks, and recursion as
+/// `loop` blocks.
+pub fn mermaid<P: Describe>(us: &str, them: &str) -> String {
+    let mut out = String::from("sequenceDiagram\n");
+    writeln!(out, "    participant {}", us).unwrap();
+    writeln!(out, "    participant {}", them).unwrap();
+    sequence(&mut out, &P::describe(), us, them, 1);
+    out
+}
+
+fn sequence(out: &mut String, s: &Session, us: &str, them: &str, depth: usize) {
+    let indent = "    ".repeat(depth);
+    match s {
+        Session::Eps => {
+            writeln!(out, "{}Note over {},{}: close", indent, us, them).unwrap();
+        }
+        Session::Send(t, p) => {
+            writeln!(out, "{}{}->>{}: {}", indent, us, them, mermaid_text(&short_name(t))).unwrap();
+            sequence(out, p, us, them, depth);
+        }
+        Session::Recv(t, p) => {
+            writeln!(out, "{}{}->>{}: {}", indent, them, us, mermaid_text(&short_name(t))).unwrap();
+            sequence(out, p, us, them, depth);
+        }
+        Session::Choose(p, q) => branches(out, p, q, us, (us, them), depth),
+        Session::Offer(p, q) => branches(out, p, q, them, (us, them), depth),
+        Session::Rec(p) => {
+            writeln!(out, "{}loop", indent).unwrap();
+            sequence(out, p, us, them, depth + 1);
+            writeln!(out, "{}end", indent).unwrap();
+        }
+        Session::Var(n) => {
+            writeln!(out, "{}Note over {},{}: continue loop {}", indent, us, them, n).unwrap();
+        }
+    }
+}
+
+fn branches(out: &mut String,
+            p: &Session,
+            q: &Session,
+            chooser: &str,
+            (us, them): (&str, &str),
+            depth: usize)
+{
+    let indent = "    ".repeat(depth);
+    writeln!(out, "{}alt {} selects 0", indent, chooser).unwrap();
+    sequence(out, p, us, them, depth + 1);
+    writeln!(out, "{}else {} selects 1", indent, chooser).unwrap();
+    sequence(out, q, us, them, depth + 1);
+    writeln!(out, "{}end", indent).unwrap();
+}
+
+/// Render a Graphviz state diagram of the protocol `P`, as seen from `us`.
+///
+/// Each state of the channel is a node, and each edge is labeled with the
+/// message or choice which moves the protocol along. Recursion variables
+/// become edges back to the state their `Rec` started in.
+pub fn dot<P: Describe>(us: &str, them: &str) -> String {
+    let mut graph = Graph { us, them, states: 0, body: String::new() };
+    let start = graph.state(&P::describe(), None, &mut vec![]);
+    let mut out = String::from("digraph {\n");
+    writeln!(out, "    start [shape=point];").unwrap();
+    writeln!(out, "    start -> s{};", start).unwrap();
+    out.push_str(&graph.body);
+    out.push_str("}\n");
+    out
+}
+
+struct Graph<'a> {
+    us: &'a str,
+    them: &'a str,
+    states: usize,
+    body: String,
+}
+
+impl<'a> Graph<'a> {
+    /// Add the states for `s`, returning the state it starts in. `at` reuses
+    /// an existing state for the start, and `recs` is the stack of states
+    /// recursion variables refer to. A variable with no `Rec` to refer to
+    /// gets a state of its own, marked unbound.
+    fn state(&mut self, s: &Session, at: Option<usize>, recs: &mut Vec<usize>) -> usize {
+        if let Session::Var(n) = s {
+            if let Some(i) = recs.len().checked_sub(n + 1) {
+                return recs[i];
+            }
+        }
+        let id = at.unwrap_or_else(|| self.node(s));
+        match s {
+            Session::Eps | Session::Var(_) => {}
+            Session::Send(t, p) => {
+                let label = format!("{} → {}: {}", self.us, self.them, short_name(t));
+                let next = self.state(p, None, recs);
+                self.edge(id, next, &label);
+            }
+            Session::Recv(t, p) => {
+                let label = format!("{} → {}: {}", self.them, self.us, short_name(t));
+                let next = self.state(p, None, recs);
+                self.edge(id, next, &label);
+            }
+            Session::Choose(p, q) | Session::Offer(p, q) => {
+                let chooser = if let Session::Choose(..) = s { self.us } else { self.them };
+                let left = self.state(p, None, recs);
+                let right = self.state(q, None, recs);
+                self.edge(id, left, &format!("{} selects 0", chooser));
+                self.edge(id, right, &format!("{} selects 1", chooser));
+            }
+            Session::Rec(p) => {
+                recs.push(id);
+                self.state(p, Some(id), recs);
+                recs.pop();
+            }
+        }
+        id
+    }
+
+    fn node(&mut self, s: &Session) -> usize {
+        let id = self.states;
+        self.states += 1;
+        match s {
+            Session::Eps => {
+                writeln!(self.body, "    s{} [label=\"close\", shape=doublecircle];", id).unwrap();
+            }
+            Session::Var(n) => {
+                writeln!(self.body, "    s{} [label=\"unbound {}\", shape=octagon];", id, n).unwrap();
+            }
+            _ => {
+                writeln!(self.body, "    s{} [label=\"{}\", shape=circle];", id, id).unwrap();
+            }
+        }
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        writeln!(self.body, "    s{} -> s{} [label=\"{}\"];", from, to, dot_text(label)).unwrap();
+    }
+}
+
+/// Escape text for a Mermaid message, where `;` and `#` are syntax and `<`
+/// and `>` would be read as HTML, using Mermaid's entity codes.
+fn mermaid_text(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '#' | ';' | '"' | '|' | '<' | '>' => write!(out, "#{};", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Escape text for a quoted Graphviz string.
+fn dot_text(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Strip the module paths from a type's name, so `alloc::string::String`
+/// becomes just `String`.
+fn short_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut segment = 0;
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            out.truncate(segment);
+        } else {
+            out.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                segment = out.len();
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::*;
+
+    type Admittance = Recv<u64, Choose<Send<u64, Eps>, Eps>>;
+    type Ping = Rec<Offer<Eps, Recv<u8, Send<String, Var<Z>>>>>;
+
+    #[test]
+    fn short_names() {
+        assert_eq!("String", short_name("alloc::string::String"));
+        assert_eq!("(u64, Vec<String>)",
+                   short_name("(u64, alloc::vec::Vec<alloc::string::String>)"));
+    }
+
+    #[test]
+    fn mermaid_branches() {
+        let expected = "\
+sequenceDiagram
+    participant Admittor
+    participant Client
+    Client->>Admittor: u64
+    alt Admittor selects 0
+        Admittor->>Client: u64
+        Note over Admittor,Client: close
+    else Admittor selects 1
+        Note over Admittor,Client: close
+    end
+";
+        assert_eq!(expected, mermaid::<Admittance>("Admittor", "Client"));
+    }
+
+    #[test]
+    fn mermaid_loops() {
+        let diagram = mermaid::<Ping>("A", "B");
+        assert!(diagram.contains("    loop\n        alt B selects 0\n"));
+        assert!(diagram.contains("            B->>A: u8\n            A->>B: String\n"));
+        assert!(diagram.contains("Note over A,B: continue loop 0\n        end\n    end\n"));
+    }
+
+    #[test]
+    fn dot_loops() {
+        let expected = "\
+digraph {
+    start [shape=point];
+    start -> s0;
+    s0 [label=\"0\", shape=circle];
+    s1 [label=\"close\", shape=doublecircle];
+    s2 [label=\"2\", shape=circle];
+    s3 [label=\"3\", shape=circle];
+    s3 -> s0 [label=\"A → B: String\"];
+    s2 -> s3 [label=\"B → A: u8\"];
+    s0 -> s1 [label=\"B selects 0\"];
+    s0 -> s2 [label=\"B selects 1\"];
+}
+";
+        assert_eq!(expected, dot::<Ping>("A", "B"));
+    }
+
+    #[test]
+    fn escapes() {
+        let diagram = mermaid::<Send<Vec<u8>, Eps>>("A", "B");
+        assert!(diagram.contains("    A->>B: Vec#60;u8#62;\n"));
+        let diagram = dot::<Send<u8, Eps>>("\"A\"", "B\\");
+        assert!(diagram.contains("s0 -> s1 [label=\"\\\"A\\\" → B\\\\: u8\"];"));
+    }
+
+    #[test]
+    fn dot_unbound() {
+        let expected = "\
+digraph {
+    start [shape=point];
+    start -> s0;
+    s0 [label=\"0\", shape=circle];
+    s1 [label=\"unbound 0\", shape=octagon];
+    s0 -> s1 [label=\"A → B: u8\"];
+}
+";
+        assert_eq!(expected, dot::<Send<u8, Var<Z>>>("A", "B"));
+    }
+}
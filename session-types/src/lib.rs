@@ -1,5 +1,7 @@
 use std::fmt::Debug;
+use std::io::Error;
 use std::marker::{self, PhantomData};
+use std::net::ToSocketAddrs;
 use std::mem::transmute;
 use std::thread;
 use std::time::Duration;
@@ -50,11 +52,27 @@ impl<P: Dual> Dual for Rec<P> {
     type Dual = Rec<P::Dual>;
 }
 
+impl<N> Dual for Var<N> {
+    type Dual = Var<N>;
+}
+
+pub mod check;
+pub mod describe;
+pub mod diagram;
+pub mod mock;
+pub mod select;
+pub mod serve;
+
 use channels::Channel;
+use describe::Describe;
 
+/// A session typed channel, with environment `E` and protocol `P`.
+///
+/// New sessions can only be created in their initial state, with `()` as the
+/// environment, by the constructors below.
 pub struct Chan<E,P>(
-    pub Channel,
-    pub PhantomData<(E,P)>,
+    Channel,
+    PhantomData<(E,P)>,
 );
 
 // TODO: We're working with serde/bincode TCP channels, this will be cool to
@@ -69,24 +87,70 @@ pub fn connect<F1, F2, P>(srv: F1, cli: F2)
 where
     F1: Fn(Chan<(), P>) + marker::Send + 'static,
     F2: Fn(Chan<(), P::Dual>) + marker::Send + 'static,
-    P: Dual + marker::Send + 'static,
-    P::Dual: Dual + marker::Send + 'static
+    P: Dual + Describe + marker::Send + 'static,
+    P::Dual: Dual + Describe + marker::Send + 'static
 {
     let t = thread::spawn(move || {
-        let s = Channel::accept_from_socket_addr("127.0.0.1:1337").unwrap();
-        let c = Chan(s, PhantomData);
-        srv(c);
+        srv(Chan::accept("127.0.0.1:1337").unwrap());
     });
     thread::sleep(Duration::from_millis(10));
-    let c = Channel::connect_to_socket_addr("nixpulvis".into(), "127.0.0.1:1337").unwrap();
-    cli(Chan(c, PhantomData));
+    cli(Chan::connect("127.0.0.1:1337", "nixpulvis".into()).unwrap());
     t.join().unwrap();
 }
 
+/// Session establishment.
+///
+/// Both ends of a new session agree on the protocol before it starts, by
+/// checking the connecting end's protocol fingerprint against the dual of
+/// the accepting end's. Builds with mismatched session types fail here with
+/// a "protocol mismatch" error, instead of somewhere in the middle of the
+/// protocol.
+impl<P: Describe> Chan<(), P> {
+    /// Accept a client on `socket_addr`, and start a session with it.
+    pub fn accept<A: ToSocketAddrs + Debug>(socket_addr: A) -> Result<Self, Error> {
+        Self::accept_from_channel(Channel::accept_from_socket_addr(socket_addr)?)
+    }
+
+    /// Connect to the server on `socket_addr` as `identity`, and start a
+    /// session with it.
+    pub fn connect<A: ToSocketAddrs>(socket_addr: A, identity: String) -> Result<Self, Error> {
+        Self::connect_to_channel(Channel::connect_to_socket_addr(identity, socket_addr)?)
+    }
+
+    /// Start a session on a channel accepted from a client.
+    pub fn accept_from_channel(mut channel: Channel) -> Result<Self, Error> {
+        let expected = P::describe().dual().fingerprint();
+        let theirs = channel.accept_call(&|fingerprint: &u64| *fingerprint == expected)?;
+        if theirs == expected {
+            info!("session accepted: {:016x}", expected);
+            Ok(Chan(channel, PhantomData))
+        } else {
+            let error = format!("protocol mismatch: expected {:016x}, got {:016x}", expected, theirs);
+            Err(Error::other(error))
+        }
+    }
+
+    /// Start a session on a channel connected to a server.
+    pub fn connect_to_channel(mut channel: Channel) -> Result<Self, Error> {
+        let ours = P::describe().fingerprint();
+        if channel.call(&ours)? {
+            info!("session connected: {:016x}", ours);
+            Ok(Chan(channel, PhantomData))
+        } else {
+            let error = format!("protocol mismatch: peer rejected {:016x}", ours);
+            Err(Error::other(error))
+        }
+    }
+}
+
 impl<E> Chan<E, Eps> {
     /// Close a channel. Should always be used at the end of your program.
-    pub fn close(self) {
+    ///
+    /// Both sides close together, so an error here means the other side
+    /// didn't reach the end of the session cleanly.
+    pub fn close(self) -> Result<(), Error> {
         info!("closing session");
+        self.0.close()
     }
 }
 
@@ -121,7 +185,7 @@ where T: for<'de> Deserialize<'de> + Debug
         info!("receiving...");
         let v = self.0.recv().unwrap();
         info!("received {:?}", v);
-        unsafe { (transmute(self), v) }
+        unsafe { (transmute::<Chan<E, Recv<T, P>>, Chan<E, P>>(self), v) }
     }
 }
 
@@ -151,10 +215,10 @@ impl<E, P, Q> Chan<E, Offer<P, Q>> {
         info!("offering...");
         if self.0.recv().unwrap() {
             info!("offered 0");
-            Branch::Left(unsafe { transmute(self) })
+            Branch::Left(unsafe { transmute::<Chan<E, Offer<P, Q>>, Chan<E, P>>(self) })
         } else {
             info!("offered 1");
-            Branch::Right(unsafe { transmute(self) })
+            Branch::Right(unsafe { transmute::<Chan<E, Offer<P, Q>>, Chan<E, Q>>(self) })
         }
     }
 }
@@ -177,13 +241,13 @@ mod tests {
     type Hi = Send<String, Eps>;
 
     fn sender(c: Chan<(), Hi>) {
-        c.send("hi".into()).close();
+        c.send("hi".into()).close().unwrap();
     }
 
     fn receiver(c: Chan<(), <Hi as Dual>::Dual>) {
         let (c, s) = c.recv();
         assert_eq!("hi", s);
-        c.close();
+        c.close().unwrap();
     }
 
     #[test]
@@ -196,22 +260,21 @@ mod tests {
     fn offerer(c: Chan<(), Opf>) {
         match c.offer() {
             Branch::Left(c) => {
-                c.send(42).close();
+                c.send(42).close().unwrap();
             }
             Branch::Right(c) => {
-                c.close();
+                c.close().unwrap();
             }
         }
     }
 
     fn chooser(c: Chan<(), <Opf as Dual>::Dual>) {
-        let r = 6; // TODO: gen rand.
-        if r % 2 == 0 {
+        if rand::random() {
             let (c, v) = c.sel0().recv();
             assert_eq!(42, v);
-            c.close();
+            c.close().unwrap();
         } else {
-            c.sel1().close()
+            c.sel1().close().unwrap()
         }
     }
 
@@ -220,6 +283,98 @@ mod tests {
         connect(offerer, chooser);
     }
 
+    #[test]
+    fn offer_choose_random() {
+        check::check(100, offerer);
+    }
+
+    #[test]
+    fn replay_session() {
+        use std::env;
+        use std::fs::File;
+        use channels::transcript::{self, Replay};
+
+        let path = env::temp_dir().join("acolytes-replay-session.transcript");
+        let (mut c, s) = Channel::memory_pair("nixpulvis".into());
+        c.record("hi".into(), File::create(&path).unwrap());
+        let t = thread::spawn(move || sender(Chan::accept_from_channel(s).unwrap()));
+        receiver(Chan::connect_to_channel(c).unwrap());
+        t.join().unwrap();
+
+        // The receiver runs again against the recording, without a sender.
+        let frames = transcript::read(File::open(&path).unwrap()).unwrap();
+        let c = Channel::replay(Replay::new("hi", frames));
+        receiver(Chan::connect_to_channel(c).unwrap());
+    }
+
+    #[test]
+    fn truncated_session() {
+        use channels::fault::{Fault, Faulty};
+        use channels::memory;
+
+        // Cut the stream off in the middle of "hi", after the handshakes.
+        let (a, b) = memory::pair();
+        let t = thread::spawn(move || {
+            let c = Channel::connect_to_transport("nixpulvis".into(), b).unwrap();
+            receiver(Chan::connect_to_channel(c).unwrap());
+        });
+        let faulty = Faulty::new(a).on_write(14, Fault::Truncate);
+        let c = Channel::accept_from_transport(faulty).unwrap();
+        let s = thread::spawn(move || sender(Chan::accept_from_channel(c).unwrap()));
+        // Neither side gets to close the session cleanly.
+        assert!(s.join().is_err());
+        assert!(t.join().is_err());
+    }
+
+    #[test]
+    fn protocol_mismatch() {
+        let t = thread::spawn(move || {
+            let result = Chan::<(), Hi>::accept("127.0.0.1:1338");
+            assert!(matches!(result, Err(e) if e.to_string().starts_with("protocol mismatch")));
+        });
+        thread::sleep(Duration::from_millis(10));
+        let result = Chan::<(), Recv<u64, Eps>>::connect("127.0.0.1:1338", "nixpulvis".into());
+        assert!(matches!(result, Err(e) if e.to_string().starts_with("protocol mismatch")));
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn multiplexed_sessions() {
+        use channels::mux::Mux;
+
+        // Two differently typed sessions over one channel.
+        let (a, b) = Channel::memory_pair("nixpulvis".into());
+        let (a, b) = (Mux::new(a).unwrap(), Mux::new(b).unwrap());
+        let (hi, opf) = (a.open().unwrap(), a.open().unwrap());
+        let t = thread::spawn(move || {
+            let hi = b.accept().unwrap();
+            let opf = b.accept().unwrap();
+            let t = thread::spawn(|| chooser(Chan::connect_to_channel(opf).unwrap()));
+            receiver(Chan::connect_to_channel(hi).unwrap());
+            t.join().unwrap();
+        });
+        let o = thread::spawn(|| offerer(Chan::accept_from_channel(opf).unwrap()));
+        sender(Chan::accept_from_channel(hi).unwrap());
+        o.join().unwrap();
+        t.join().unwrap();
+    }
+
+    #[test]
+    fn simulated_session() {
+        use channels::sim::Network;
+
+        // Every seed picks a different latency and interleaving.
+        for seed in 0..10 {
+            let mut net = Network::new(seed);
+            let (a, b) = (net.node("offerer"), net.node("chooser"));
+            let latency = Duration::from_millis(1)..Duration::from_millis(50);
+            let (c, s) = net.link(a, b, latency);
+            net.spawn(a, move || offerer(Chan::accept_from_channel(c).unwrap()));
+            net.spawn(b, move || chooser(Chan::connect_to_channel(s).unwrap()));
+            assert!(net.run() >= Duration::from_millis(2));
+        }
+    }
+
     // #[test]
     // fn var() {}
     // #[test]
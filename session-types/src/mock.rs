@@ -0,0 +1,288 @@
+//! Scripted mock peers, for testing one end of a protocol on its own.
+//!
+//! A `Script` is written from the mock's side of the protocol, with the same
+//! steps a `Chan` would take, so it can only describe runs the session type
+//! allows. Spawning it returns the other end of an in-memory session for the
+//! code under test, and a `Mock` to check the peer saw what it expected.
+//!
+//! ```rust,ignore
+//! // Test the client of `Recv<u64, Send<bool, Eps>>` on its own.
+//! let (c, mock) = Script::<Recv<u64, Send<bool, Eps>>>::new()
+//!     .expect(42)
+//!     .send(true)
+//!     .spawn();
+//! client(c);
+//! mock.join().unwrap();
+//! ```
+use std::error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::thread::{self, JoinHandle};
+use channels::Channel;
+use log::error;
+use super::*;
+
+type Step = Box<dyn FnOnce(&mut Channel) -> Result<(), String> + marker::Send>;
+
+/// The steps a mock peer takes, starting with protocol `S`. `E` and `P` are
+/// the environment and protocol after the steps so far.
+pub struct Script<S, E = (), P = S> {
+    steps: Vec<Step>,
+    phantom: PhantomData<(S, E, P)>,
+}
+
+/// A difference between what the mock peer expected and what happened.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// The index of the step which failed.
+    pub step: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "step {}: {}", self.step, self.message)
+    }
+}
+
+impl error::Error for Mismatch {}
+
+/// A running mock peer.
+pub struct Mock(JoinHandle<Result<(), Mismatch>>);
+
+impl Mock {
+    /// Wait for the mock to finish its script. A step which panics is
+    /// reported as a mismatch at that step.
+    pub fn join(self) -> Result<(), Mismatch> {
+        self.0.join().expect("mock panicked outside a step")
+    }
+}
+
+/// Run a step, turning a panic into its error.
+fn run(step: Step, c: &mut Channel) -> Result<(), String> {
+    panic::catch_unwind(AssertUnwindSafe(|| step(c))).unwrap_or_else(|payload| {
+        let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown".into());
+        Err(format!("panicked: {}", message))
+    })
+}
+
+impl<S> Script<S> {
+    pub fn new() -> Script<S> {
+        Script { steps: vec![], phantom: PhantomData }
+    }
+}
+
+impl<S> Default for Script<S> {
+    fn default() -> Script<S> {
+        Script::new()
+    }
+}
+
+impl<S, E, P> Script<S, E, P> {
+    fn step<Q, F>(mut self, step: F) -> Script<S, E, Q>
+    where F: FnOnce(&mut Channel) -> Result<(), String> + marker::Send + 'static
+    {
+        self.steps.push(Box::new(step));
+        Script { steps: self.steps, phantom: PhantomData }
+    }
+
+    fn jump<F, Q>(self) -> Script<S, F, Q> {
+        Script { steps: self.steps, phantom: PhantomData }
+    }
+}
+
+impl<S, E> Script<S, E, Eps> where S: Dual {
+    /// Start the mock peer, returning the session for the code under test.
+    pub fn spawn(self) -> (Chan<(), S::Dual>, Mock) {
+        let (mut ours, theirs) = Channel::memory_pair("mock".into());
+        let steps = self.steps;
+        let t = thread::spawn(move || {
+            let n = steps.len();
+            for (i, step) in steps.into_iter().enumerate() {
+                if let Err(message) = run(step, &mut ours) {
+                    error!("mock mismatch at step {}: {}", i, message);
+                    return Err(Mismatch { step: i, message });
+                }
+            }
+            ours.close().map_err(|e| Mismatch { step: n, message: format!("closing: {}", e) })
+        });
+        (Chan(theirs, PhantomData), Mock(t))
+    }
+}
+
+impl<S, E, P, T> Script<S, E, Send<T, P>>
+where T: Serialize + Debug + marker::Send + 'static
+{
+    /// Send `v` to the code under test.
+    pub fn send(self, v: T) -> Script<S, E, P> {
+        self.step(move |c| c.send(&v).map_err(|e| format!("sending {:?}: {}", v, e)))
+    }
+}
+
+impl<S, E, P, T> Script<S, E, Recv<T, P>>
+where T: for<'de> Deserialize<'de> + Debug + marker::Send + 'static
+{
+    /// Receive any value from the code under test.
+    pub fn recv(self) -> Script<S, E, P> {
+        self.step(|c| c.recv::<T>().map(|_| ()).map_err(|e| format!("receiving: {}", e)))
+    }
+
+    /// Receive exactly `v` from the code under test.
+    pub fn expect(self, v: T) -> Script<S, E, P>
+        where T: PartialEq
+    {
+        self.step(move |c| {
+            let received = c.recv::<T>().map_err(|e| format!("receiving: {}", e))?;
+            if received == v {
+                Ok(())
+            } else {
+                Err(format!("expected {:?}, received {:?}", v, received))
+            }
+        })
+    }
+}
+
+impl<S, E, P, Q> Script<S, E, Choose<P, Q>> {
+    /// Select protocol `P`.
+    pub fn sel0(self) -> Script<S, E, P> {
+        self.step(|c| c.send(&true).map_err(|e| format!("selecting 0: {}", e)))
+    }
+
+    /// Select protocol `Q`.
+    pub fn sel1(self) -> Script<S, E, Q> {
+        self.step(|c| c.send(&false).map_err(|e| format!("selecting 1: {}", e)))
+    }
+}
+
+impl<S, E, P, Q> Script<S, E, Offer<P, Q>> {
+    /// Expect the code under test to select protocol `P`.
+    pub fn expect_sel0(self) -> Script<S, E, P> {
+        self.step(|c| expect_selection(c, true))
+    }
+
+    /// Expect the code under test to select protocol `Q`.
+    pub fn expect_sel1(self) -> Script<S, E, Q> {
+        self.step(|c| expect_selection(c, false))
+    }
+}
+
+fn expect_selection(c: &mut Channel, left: bool) -> Result<(), String> {
+    let received: bool = c.recv().map_err(|e| format!("offering: {}", e))?;
+    if received == left {
+        Ok(())
+    } else {
+        Err(format!("expected selection {}, received {}", !left as u8, !received as u8))
+    }
+}
+
+impl<S, E, P> Script<S, E, Rec<P>> {
+    /// Enter a recursive environment.
+    pub fn enter(self) -> Script<S, (P, E), P> {
+        self.jump()
+    }
+}
+
+impl<S, E, P> Script<S, (P, E), Var<Z>> {
+    /// Recurse to the environment on the top of the environment stack.
+    pub fn zero(self) -> Script<S, (P, E), P> {
+        self.jump()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Server = Recv<u64, Choose<Send<u64, Eps>, Eps>>;
+
+    fn client(c: Chan<(), <Server as Dual>::Dual>, n: u64) -> Option<u64> {
+        match c.send(n).offer() {
+            Branch::Left(c) => {
+                let (c, v) = c.recv();
+                c.close().unwrap();
+                Some(v)
+            }
+            Branch::Right(c) => {
+                c.close().unwrap();
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn scripted_branches() {
+        let (c, mock) = Script::<Server>::new().expect(1).sel0().send(2).spawn();
+        assert_eq!(Some(2), client(c, 1));
+        mock.join().unwrap();
+
+        let (c, mock) = Script::<Server>::new().recv().sel1().spawn();
+        assert_eq!(None, client(c, 1));
+        mock.join().unwrap();
+    }
+
+    #[test]
+    fn mismatched_value() {
+        let (c, mock) = Script::<Server>::new().expect(1).sel1().spawn();
+        // The mock hangs up, so the client's offer fails.
+        assert!(thread::spawn(move || client(c, 2)).join().is_err());
+        let mismatch = mock.join().unwrap_err();
+        assert_eq!(0, mismatch.step);
+        assert_eq!("expected 1, received 2", mismatch.message);
+    }
+
+    #[test]
+    fn mismatched_selection() {
+        type Choice = Offer<Eps, Send<u8, Eps>>;
+        let (c, mock) = Script::<Choice>::new().expect_sel1().send(1).spawn();
+        assert!(c.sel0().close().is_err());
+        let mismatch = mock.join().unwrap_err();
+        assert_eq!("expected selection 1, received 0", mismatch.message);
+    }
+
+    #[test]
+    fn scripted_loop() {
+        type Counter = Rec<Offer<Eps, Recv<u8, Var<Z>>>>;
+        let (c, mock) = Script::<Counter>::new()
+            .enter().expect_sel1().expect(1)
+            .zero().expect_sel1().expect(2)
+            .zero().expect_sel0()
+            .spawn();
+        let c = c.enter().sel1().send(1).zero().sel1().send(2).zero();
+        c.sel0().close().unwrap();
+        mock.join().unwrap();
+    }
+
+    #[test]
+    fn panicking_step() {
+        struct Bad;
+
+        impl Serialize for Bad {
+            fn serialize<S: serde::Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+                panic!("can't serialize")
+            }
+        }
+
+        impl Debug for Bad {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write!(f, "Bad")
+            }
+        }
+
+        let (c, mock) = Script::<Recv<u8, Send<Bad, Eps>>>::new().expect(1).send(Bad).spawn();
+        drop(c.send(1));
+        let mismatch = mock.join().unwrap_err();
+        assert_eq!(1, mismatch.step);
+        assert_eq!("panicked: can't serialize", mismatch.message);
+    }
+
+    #[test]
+    fn recv_without_eq() {
+        use std::sync::Mutex;
+
+        let (c, mock) = Script::<Recv<Mutex<u64>, Eps>>::new().recv().spawn();
+        c.send(Mutex::new(1)).close().unwrap();
+        mock.join().unwrap();
+    }
+}
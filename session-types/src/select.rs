@@ -0,0 +1,141 @@
+//! Waiting on many sessions at once.
+//!
+//! Like `hselect` and `ChanSelect` from the original paper, these block until
+//! one of a set of channels has something to receive, so a single thread can
+//! drive many sessions. Only channels whose next step is the peer's (`Recv`
+//! or `Offer`) can be selected over, anything else would wait forever.
+//!
+//! Waiting is done by `Channel::select`, so sessions over TCP are waited on
+//! all at once, and others each for a moment in turn.
+use std::io::Error;
+use channels::Channel;
+use super::*;
+
+/// Protocol states where the next move belongs to the other end.
+pub trait Incoming {}
+
+impl<T, P> Incoming for Recv<T, P> {}
+
+impl<P, Q> Incoming for Offer<P, Q> {}
+
+/// Block until one of the given channels is ready, returning its index.
+fn wait(channels: &[&Channel]) -> Result<usize, Error> {
+    let i = Channel::select(channels)?;
+    info!("selected {}", i);
+    Ok(i)
+}
+
+/// The channel `hselect` found ready, and the rest.
+pub type Selected<E, P> = (Chan<E, P>, Vec<Chan<E, P>>);
+
+/// Homogeneous select. Takes a vector of channels all in the same state, and
+/// returns the first one that's ready along with the rest.
+pub fn hselect<E, P: Incoming>(mut chans: Vec<Chan<E, P>>) -> Result<Selected<E, P>, Error> {
+    let i = wait(&chans.iter().map(|c| &c.0).collect::<Vec<_>>())?;
+    let c = chans.remove(i);
+    Ok((c, chans))
+}
+
+/// Heterogeneous select. Channels in any incoming state can be added, and
+/// `wait` returns the index of the first one that's ready, in the order they
+/// were added.
+#[derive(Default)]
+pub struct ChanSelect<'c> {
+    channels: Vec<&'c Channel>,
+}
+
+impl<'c> ChanSelect<'c> {
+    pub fn new() -> ChanSelect<'c> {
+        ChanSelect { channels: vec![] }
+    }
+
+    /// Add a channel waiting to receive a value.
+    pub fn add_recv<E, P, T>(&mut self, chan: &'c Chan<E, Recv<T, P>>) -> usize {
+        self.channels.push(&chan.0);
+        self.channels.len() - 1
+    }
+
+    /// Add a channel waiting for the other end to make a choice.
+    pub fn add_offer<E, P, Q>(&mut self, chan: &'c Chan<E, Offer<P, Q>>) -> usize {
+        self.channels.push(&chan.0);
+        self.channels.len() - 1
+    }
+
+    /// Block until one of the added channels is ready.
+    pub fn wait(self) -> Result<usize, Error> {
+        wait(&self.channels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+    use super::*;
+
+    fn pair(addr: &'static str) -> (Channel, Channel) {
+        let t = thread::spawn(move || Channel::accept_from_socket_addr(addr).unwrap());
+        thread::sleep(Duration::from_millis(10));
+        let c = Channel::connect_to_socket_addr("nixpulvis".into(), addr).unwrap();
+        (t.join().unwrap(), c)
+    }
+
+    #[test]
+    fn hselect_ready() {
+        let (s0, c0) = pair("127.0.0.1:1340");
+        let (s1, c1) = pair("127.0.0.1:1341");
+        let servers: Vec<Chan<(), Recv<u64, Eps>>> =
+            vec![Chan(s0, PhantomData), Chan(s1, PhantomData)];
+        let c1: Chan<(), Send<u64, Eps>> = Chan(c1, PhantomData);
+        let t = thread::spawn(move || c1.send(1).close().unwrap());
+
+        let (ready, rest) = hselect(servers).unwrap();
+        let (ready, v) = ready.recv();
+        assert_eq!(1, v);
+        ready.close().unwrap();
+        assert_eq!(1, rest.len());
+        t.join().unwrap();
+        drop(c0);
+    }
+
+    #[test]
+    fn chan_select() {
+        let (s0, c0) = pair("127.0.0.1:1342");
+        let (s1, c1) = pair("127.0.0.1:1343");
+        let s0: Chan<(), Recv<u64, Eps>> = Chan(s0, PhantomData);
+        let s1: Chan<(), Offer<Eps, Eps>> = Chan(s1, PhantomData);
+        let c1: Chan<(), Choose<Eps, Eps>> = Chan(c1, PhantomData);
+        let t = thread::spawn(move || c1.sel1().close().unwrap());
+
+        let mut select = ChanSelect::new();
+        assert_eq!(0, select.add_recv(&s0));
+        assert_eq!(1, select.add_offer(&s1));
+        assert_eq!(1, select.wait().unwrap());
+        match s1.offer() {
+            Branch::Left(_) => panic!("expected right"),
+            Branch::Right(c) => c.close().unwrap(),
+        }
+        t.join().unwrap();
+        drop((s0, c0));
+    }
+
+    #[test]
+    fn hselect_memory() {
+        let (s0, _c0) = Channel::memory_pair("nixpulvis".into());
+        let (s1, c1) = Channel::memory_pair("nixpulvis".into());
+        let servers: Vec<Chan<(), Recv<u64, Eps>>> =
+            vec![Chan(s0, PhantomData), Chan(s1, PhantomData)];
+        let c1: Chan<(), Send<u64, Eps>> = Chan(c1, PhantomData);
+        let t = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            c1.send(1).close().unwrap()
+        });
+
+        let (ready, rest) = hselect(servers).unwrap();
+        let (ready, v) = ready.recv();
+        assert_eq!(1, v);
+        ready.close().unwrap();
+        assert_eq!(1, rest.len());
+        t.join().unwrap();
+    }
+}
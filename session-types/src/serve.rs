@@ -0,0 +1,256 @@
+//! Serving many sessions from one listener.
+use std::io::{Error, ErrorKind};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use channels::Channel;
+use log::{info, error};
+use super::*;
+
+/// How long the accepting thread sleeps between checks for shutdown.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a client has to finish establishing its session, by default.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A running session server, see `serve`.
+pub struct Server {
+    shutdown: Arc<AtomicBool>,
+    acceptor: JoinHandle<Result<(), Error>>,
+    stopped: Receiver<()>,
+    handshake_timeout: Arc<Mutex<Duration>>,
+}
+
+impl Server {
+    /// Set how long a client has to finish establishing its session, for
+    /// connections accepted from now on.
+    pub fn handshake_timeout(self, timeout: Duration) -> Server {
+        *self.handshake_timeout.lock().unwrap() = timeout;
+        self
+    }
+
+    /// Stop accepting connections, and wait up to `timeout` for every running
+    /// session to finish, failing with `ErrorKind::TimedOut` if they don't.
+    /// Sessions still running are left to finish on their own.
+    pub fn shutdown(self, timeout: Duration) -> Result<(), Error> {
+        info!("shutting down server");
+        self.shutdown.store(true, Ordering::SeqCst);
+        match self.stopped.recv_timeout(timeout) {
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::new(ErrorKind::TimedOut, "sessions still running"));
+            }
+            // Sent when the acceptor is done, or dropped if it panicked.
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => {}
+        }
+        self.acceptor.join().map_err(|_| Error::other("server acceptor panicked"))?
+    }
+}
+
+/// Accept authenticated connections from `listener`, running `handler` on a
+/// new session with protocol `P` for each one.
+///
+/// Sessions are run on a pool of `max_sessions` threads, and connections
+/// wait to be accepted while they are all busy. A handler which panics only
+/// ends its own session.
+///
+/// Clients have `HANDSHAKE_TIMEOUT` to establish their session, unless
+/// changed with `Server::handshake_timeout`.
+pub fn serve<P, F>(listener: TcpListener, max_sessions: usize, handler: F)
+    -> Result<Server, Error>
+where
+    P: Describe + 'static,
+    F: Fn(Chan<(), P>) + marker::Send + Sync + 'static,
+{
+    assert!(max_sessions > 0, "server needs at least one session");
+    listener.set_nonblocking(true)?;
+    info!("serving on: {:?}", listener.local_addr());
+
+    let (tx, rx) = mpsc::sync_channel::<TcpStream>(0);
+    let rx = Arc::new(Mutex::new(rx));
+    let handler = Arc::new(handler);
+    let handshake_timeout = Arc::new(Mutex::new(HANDSHAKE_TIMEOUT));
+    let workers = (0..max_sessions).map(|_| {
+        let rx = rx.clone();
+        let handler = handler.clone();
+        let timeout = handshake_timeout.clone();
+        thread::spawn(move || work(&rx, &*handler, &timeout))
+    }).collect::<Vec<_>>();
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let stop = shutdown.clone();
+    let (stopping, stopped) = mpsc::channel();
+    let acceptor = thread::spawn(move || {
+        while !stop.load(Ordering::SeqCst) {
+            let mut stream = match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("accepting client: {:?}", addr);
+                    stream
+                }
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                    continue;
+                }
+                Err(e) => {
+                    error!("accept error: {}", e);
+                    continue;
+                }
+            };
+            // Hand the stream to the next free worker.
+            loop {
+                match tx.try_send(stream) {
+                    Ok(()) => break,
+                    Err(TrySendError::Full(s)) if !stop.load(Ordering::SeqCst) => {
+                        stream = s;
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    Err(TrySendError::Full(s)) | Err(TrySendError::Disconnected(s)) => {
+                        info!("closing undispatched client: {:?}", s.peer_addr());
+                        s.shutdown(Shutdown::Both).ok();
+                        break;
+                    }
+                }
+            }
+        }
+        drop(tx);
+        let mut result = Ok(());
+        for worker in workers {
+            if worker.join().is_err() {
+                result = Err(Error::other("server worker panicked"));
+            }
+        }
+        stopping.send(()).ok();
+        result
+    });
+    Ok(Server { shutdown, acceptor, stopped, handshake_timeout })
+}
+
+fn work<P, F>(rx: &Mutex<Receiver<TcpStream>>, handler: &F, timeout: &Mutex<Duration>)
+where
+    P: Describe,
+    F: Fn(Chan<(), P>),
+{
+    loop {
+        let stream = match rx.lock().unwrap().recv() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let timeout = *timeout.lock().unwrap();
+        let session = handshake(stream, timeout);
+        match session {
+            Ok(c) => {
+                if panic::catch_unwind(AssertUnwindSafe(|| handler(c))).is_err() {
+                    error!("session handler panicked");
+                }
+            }
+            Err(e) => error!("session not established: {}", e),
+        }
+    }
+}
+
+/// Establish a session on a newly accepted stream, giving up if the client
+/// takes longer than `timeout`.
+fn handshake<P: Describe>(stream: TcpStream, timeout: Duration) -> Result<Chan<(), P>, Error> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(timeout))?;
+    let handle = stream.try_clone()?;
+    let session = Channel::accept_from_tcp_stream(stream)
+        .and_then(Chan::accept_from_channel)?;
+    handle.set_read_timeout(None)?;
+    Ok(session)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use super::*;
+
+    type Count = Recv<u64, Send<u64, Eps>>;
+
+    fn client(addr: &str, n: u64) -> u64 {
+        let c = Chan::<(), <Count as Dual>::Dual>::connect(addr, "nixpulvis".into()).unwrap();
+        let (c, m) = c.send(n).recv();
+        c.close().unwrap();
+        m
+    }
+
+    #[test]
+    fn serve_sessions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let active = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let (a, p) = (active.clone(), peak.clone());
+        let server = serve(listener, 2, move |c: Chan<(), Count>| {
+            let n = a.fetch_add(1, Ordering::SeqCst) + 1;
+            p.fetch_max(n, Ordering::SeqCst);
+            let (c, m) = c.recv();
+            thread::sleep(Duration::from_millis(20));
+            a.fetch_sub(1, Ordering::SeqCst);
+            c.send(m + 1).close().unwrap();
+        }).unwrap();
+
+        let clients = (0..6).map(|n| {
+            let addr = addr.clone();
+            thread::spawn(move || assert_eq!(n + 1, client(&addr, n)))
+        }).collect::<Vec<_>>();
+        for c in clients {
+            c.join().unwrap();
+        }
+        server.shutdown(Duration::from_secs(10)).unwrap();
+        assert!(peak.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn handler_panics() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, 1, |c: Chan<(), Count>| {
+            let (c, m) = c.recv();
+            assert!(m != 0, "zero");
+            c.send(m).close().unwrap();
+        }).unwrap();
+        let t = thread::spawn({
+            let addr = addr.clone();
+            move || client(&addr, 0)
+        });
+        assert!(t.join().is_err());
+        assert_eq!(1, client(&addr, 1));
+        server.shutdown(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn silent_client() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, 1, |c: Chan<(), Count>| {
+            let (c, m) = c.recv();
+            c.send(m).close().unwrap();
+        }).unwrap().handshake_timeout(Duration::from_millis(20));
+
+        // A client which never says anything only holds up the only worker
+        // until the handshake times out.
+        let _silent = TcpStream::connect(&addr).unwrap();
+        assert_eq!(1, client(&addr, 1));
+        server.shutdown(Duration::from_secs(10)).unwrap();
+    }
+
+    #[test]
+    fn shutdown_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = serve(listener, 1, |c: Chan<(), Count>| {
+            let (c, m) = c.recv();
+            thread::sleep(Duration::from_millis(500));
+            c.send(m).close().unwrap();
+        }).unwrap();
+        let t = thread::spawn(move || client(&addr, 1));
+        thread::sleep(Duration::from_millis(100));
+        let error = server.shutdown(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, error.kind());
+        assert_eq!(1, t.join().unwrap());
+    }
+}